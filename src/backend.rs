@@ -0,0 +1,336 @@
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use crate::piapi_handler::{BlockingStatus, DomainList, FIRST_QUERIES, ListedDomain, MatchSource, PiHoleError, Query, QueryCursor, SearchMatch, SearchResult, Summary};
+
+/// Gets the output of a long running action (ex. a gravity update) a line at a time
+pub type Progress<'a> = dyn Fn(&str) + Send + Sync + 'a;
+
+/// Everything the tray needs from a pihole.
+/// Implemented by AuthPiHoleAPI and by FakePiHole (in-memory, for tests and experiments)
+#[async_trait]
+pub trait PiHoleBackend: Send + Sync {
+    /// Retrieve the status of the pihole (enabled or disabled)
+    async fn status(&self) -> Result<BlockingStatus, PiHoleError>;
+
+    /// Enable the pihole
+    async fn enable(&self) -> Result<BlockingStatus, PiHoleError>;
+
+    /// Disable pihole for n seconds (0 disables until enabled again)
+    async fn disable(&self, seconds: u64) -> Result<BlockingStatus, PiHoleError>;
+
+    /// Today's numbers from the dashboard (queries, blocked, clients, ...)
+    async fn summary(&self) -> Result<Summary, PiHoleError>;
+
+    /// Entries on one of the allow/deny lists
+    async fn domains(&self, list: DomainList) -> Result<Vec<ListedDomain>, PiHoleError>;
+
+    /// Put a domain (or regex) on one of the allow/deny lists
+    async fn add_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError>;
+
+    /// Take a domain (or regex) off one of the allow/deny lists
+    async fn remove_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError>;
+
+    /// Find the list entries matching a domain (why it is blocked or allowed)
+    async fn search(&self, domain: &str) -> Result<SearchResult, PiHoleError>;
+
+    /// Queries newer than the cursor, oldest first, and the cursor to continue from next time.
+    /// Without a cursor the most recent ones
+    async fn queries(&self, cursor: Option<&QueryCursor>) -> Result<(Vec<Query>, QueryCursor), PiHoleError>;
+
+    /// Download the blocklists again (pihole -g). Each line of output is handed to progress as it comes
+    async fn update_gravity(&self, progress: &Progress<'_>) -> Result<(), PiHoleError>;
+
+    /// Open the dashboard in the default browser
+    fn open_dashboard(&self);
+
+    /// Address of the pihole, for the action history
+    fn host(&self) -> String;
+}
+
+/// State behind a FakePiHole, shared between its clones
+#[derive(Default)]
+pub struct FakeState {
+    pub enabled: bool,
+    pub timer: Option<u64>,
+    // When set every api call fails as if the host was down
+    pub unreachable: bool,
+    // Returned by summary
+    pub summary: Summary,
+    // Allow/deny list entries
+    pub domains: Vec<(DomainList, String)>,
+    // Query log, oldest first
+    pub queries: Vec<Query>,
+    // Returned by host, "fake" when empty. Lets a test tell the fakes of a group apart
+    pub host: String,
+    // When set the next gravity update waits for it to be notified, so a test can catch one running
+    pub gravity_hold: Option<Arc<Notify>>,
+    // Every call made against the fake, in order ("status", "enable", "disable 30", "add allow example.com", "open_dashboard")
+    pub calls: Vec<String>,
+}
+
+/// In-memory pihole. Clones share the same state so a test can keep one and hand the other to the tray
+#[derive(Clone, Default)]
+pub struct FakePiHole {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakePiHole {
+    /// Create a new FakePiHole with blocking enabled or disabled
+    pub fn new(enabled: bool) -> Self {
+        let fake = Self::default();
+        fake.state.lock().unwrap().enabled = enabled;
+        fake
+    }
+
+    /// Make every following call fail (or succeed again)
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.state.lock().unwrap().unreachable = unreachable;
+    }
+
+    /// Change the blocking state behind the tray's back (like someone using the web ui)
+    pub fn set_enabled(&self, enabled: bool) {
+        self.state.lock().unwrap().enabled = enabled;
+    }
+
+    /// Change the numbers returned by summary
+    pub fn set_summary(&self, summary: Summary) {
+        self.state.lock().unwrap().summary = summary;
+    }
+
+    /// Add a query to the end of the query log
+    pub fn add_query(&self, query: Query) {
+        self.state.lock().unwrap().queries.push(query);
+    }
+
+    /// Change the address returned by host
+    pub fn set_host(&self, host: &str) {
+        self.state.lock().unwrap().host = host.to_string();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    pub fn timer(&self) -> Option<u64> {
+        self.state.lock().unwrap().timer
+    }
+
+    /// Domains on the given list
+    pub fn listed(&self, list: DomainList) -> Vec<String> {
+        self.state.lock().unwrap().domains.iter()
+            .filter(|(on, _)| *on == list)
+            .map(|(_, domain)| domain.clone())
+            .collect()
+    }
+
+    /// Make the next gravity update wait (after its first line) until the returned Notify is notified
+    pub fn hold_gravity(&self) -> Arc<Notify> {
+        let hold = Arc::new(Notify::new());
+        self.state.lock().unwrap().gravity_hold = Some(hold.clone());
+        hold
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    // Record the call and fail if the fake is unreachable
+    fn call(&self, name: String) -> Result<(), PiHoleError> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(name);
+
+        if state.unreachable {
+            return Err(PiHoleError::Unreachable("Fake pihole is unreachable".to_string()));
+        }
+        Ok(())
+    }
+
+    // Build a response like the real api's
+    fn response(&self) -> BlockingStatus {
+        let state = self.state.lock().unwrap();
+        BlockingStatus {enabled: state.enabled, timer_remaining: state.timer}
+    }
+}
+
+#[async_trait]
+impl PiHoleBackend for FakePiHole {
+    async fn status(&self) -> Result<BlockingStatus, PiHoleError> {
+        self.call("status".to_string())?;
+        Ok(self.response())
+    }
+
+    async fn enable(&self) -> Result<BlockingStatus, PiHoleError> {
+        self.call("enable".to_string())?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.enabled = true;
+            state.timer = None;
+        }
+        Ok(self.response())
+    }
+
+    async fn disable(&self, seconds: u64) -> Result<BlockingStatus, PiHoleError> {
+        self.call(format!("disable {}", seconds))?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.enabled = false;
+            state.timer = if seconds != 0 {Some(seconds)} else {None};
+        }
+        Ok(self.response())
+    }
+
+    async fn summary(&self) -> Result<Summary, PiHoleError> {
+        self.call("summary".to_string())?;
+        Ok(self.state.lock().unwrap().summary.clone())
+    }
+
+    async fn domains(&self, list: DomainList) -> Result<Vec<ListedDomain>, PiHoleError> {
+        self.call(format!("domains {}", list.as_str()))?;
+        Ok(self.listed(list).into_iter().map(|domain| ListedDomain {domain, enabled: true, comment: None}).collect())
+    }
+
+    async fn add_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        self.call(format!("add {} {}", list.as_str(), domain))?;
+        let mut state = self.state.lock().unwrap();
+        if state.domains.contains(&(list, domain.to_string())) {
+            return Err(PiHoleError::Refused(format!("{} is already on the {}", domain, list.describe())));
+        }
+        state.domains.push((list, domain.to_string()));
+        Ok(())
+    }
+
+    async fn remove_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        self.call(format!("remove {} {}", list.as_str(), domain))?;
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.domains.iter().position(|entry| *entry == (list, domain.to_string())) else {
+            return Err(PiHoleError::Refused(format!("{} is not on the {}", domain, list.describe())));
+        };
+        state.domains.remove(index);
+        Ok(())
+    }
+
+    // Only knows exact entries, there are no blocklists or regexes to match against
+    async fn search(&self, domain: &str) -> Result<SearchResult, PiHoleError> {
+        self.call(format!("search {}", domain))?;
+        let matches = self.state.lock().unwrap().domains.iter()
+            .filter(|(_, listed)| listed == domain)
+            .map(|(list, listed)| SearchMatch {source: MatchSource::List(*list), entry: listed.clone(), enabled: true})
+            .collect();
+        Ok(SearchResult {domain: domain.to_string(), matches})
+    }
+
+    // The cursor is simply how many queries were handed out
+    async fn queries(&self, cursor: Option<&QueryCursor>) -> Result<(Vec<Query>, QueryCursor), PiHoleError> {
+        self.call("queries".to_string())?;
+        let log = self.state.lock().unwrap().queries.clone();
+
+        let start = match cursor {
+            Some(cursor) => cursor.last_id as usize,
+            None => log.len().saturating_sub(FIRST_QUERIES),
+        };
+        let next = QueryCursor {last_id: log.len() as u64, ..Default::default()};
+        Ok((log[start.min(log.len())..].to_vec(), next))
+    }
+
+    async fn update_gravity(&self, progress: &Progress<'_>) -> Result<(), PiHoleError> {
+        self.call("update_gravity".to_string())?;
+        progress("  [i] Fake pihole, nothing to download");
+        let hold = self.state.lock().unwrap().gravity_hold.take();
+        if let Some(hold) = hold {
+            hold.notified().await;
+        }
+        progress("  [✓] Done.");
+        Ok(())
+    }
+
+    fn open_dashboard(&self) {
+        self.state.lock().unwrap().calls.push("open_dashboard".to_string());
+    }
+
+    fn host(&self) -> String {
+        let host = &self.state.lock().unwrap().host;
+        if host.is_empty() {"fake".to_string()} else {host.clone()}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tray_functions;
+    use crate::tray_handler::FailCount;
+
+    #[tokio::test]
+    async fn toggle_flips_blocking() {
+        let fake = FakePiHole::new(true);
+
+        let status = tray_functions::toggle(&fake).await.unwrap();
+        assert!(!status.enabled);
+        assert!(!fake.is_enabled());
+        // Disabled by toggle means until enabled again
+        assert_eq!(fake.timer(), None);
+
+        let status = tray_functions::toggle(&fake).await.unwrap();
+        assert!(status.enabled);
+        assert_eq!(fake.calls(), ["status", "disable 0", "status", "enable"]);
+    }
+
+    #[tokio::test]
+    async fn toggle_fails_when_unreachable() {
+        let fake = FakePiHole::new(true);
+        fake.set_unreachable(true);
+
+        assert!(matches!(tray_functions::toggle(&fake).await, Err(PiHoleError::Unreachable(_))));
+        // Nothing is changed after the status failed
+        assert_eq!(fake.calls(), ["status"]);
+        assert!(fake.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn disable_sec_starts_the_timer() {
+        let fake = FakePiHole::new(true);
+
+        assert!(tray_functions::disable_sec(&fake, 30).await);
+        assert!(!fake.is_enabled());
+        assert_eq!(fake.timer(), Some(30));
+
+        fake.set_unreachable(true);
+        assert!(!tray_functions::disable_sec(&fake, 60).await);
+        assert_eq!(fake.timer(), Some(30));
+    }
+
+    // Poll the fake the way the tray does and count the failures
+    async fn poll(fake: &FakePiHole, fails: &mut FailCount) -> Result<bool, u8> {
+        let status = fake.status().await;
+        fails.test(|| status.as_ref().map(|status| status.enabled))
+    }
+
+    #[tokio::test]
+    async fn fail_count_reaches_max_fail() {
+        let fake = FakePiHole::new(true);
+        let mut fails = FailCount::new(3);
+        let base = std::time::Duration::from_secs(5);
+
+        assert_eq!(poll(&fake, &mut fails).await, Ok(true));
+
+        fake.set_unreachable(true);
+        for count in 1..=3 {
+            assert_eq!(poll(&fake, &mut fails).await, Err(count));
+            assert_eq!(fails.poll_interval(base), base);
+        }
+        assert_eq!(fails.max_fail(), 3);
+
+        // Past the limit the polls back off
+        assert_eq!(poll(&fake, &mut fails).await, Err(4));
+        assert_eq!(fails.poll_interval(base), base * 2);
+        assert_eq!(poll(&fake, &mut fails).await, Err(5));
+        assert_eq!(fails.poll_interval(base), base * 4);
+
+        // One answer resets the count
+        fake.set_unreachable(false);
+        assert_eq!(poll(&fake, &mut fails).await, Ok(true));
+        assert_eq!(fails.poll_interval(base), base);
+        fake.set_unreachable(true);
+        assert_eq!(poll(&fake, &mut fails).await, Err(1));
+    }
+}
@@ -0,0 +1,809 @@
+/*
+    Command line handling.
+    Without a command the tray is started, otherwise the command runs in the terminal and the program exits
+ */
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::backend::PiHoleBackend;
+use crate::block_on;
+use crate::config;
+use crate::credentials;
+use crate::history;
+use crate::logging;
+use crate::piapi_handler::{AuthPiHoleAPI, BlockingStatus, DomainList, PiHoleError};
+use crate::pihole_group::{GroupState, PiHoleGroup};
+use crate::querylog;
+use crate::temporary;
+use crate::tray_functions::{self, describe_duration, parse_duration};
+
+const USAGE: &str = "Usage: pihole-switch [--config <path>] [--json] [command]
+
+Without a command the tray icon is started. When the tray is already running,
+status/enable/disable/toggle are sent to it instead of the piholes directly.
+
+Commands:
+  status              Show whether blocking is enabled
+  enable              Enable blocking
+  disable [duration]  Disable blocking, for a duration like 30s, 15m or 1h30m
+                      (until enabled again when no duration is given)
+  toggle              Disable when enabled, enable otherwise
+  domains [allow|deny]
+                      List the domains on the allow and deny lists
+  allow <domain> [--for <duration>]
+                      Add a domain to the allowlist, for a while with --for
+                      (taken off again by the tray, or the next list command)
+  block <domain>      Add a domain to the denylist
+  unallow <domain>    Take a domain off the allowlist
+  unblock <domain>    Take a domain off the denylist
+                      (--regex after any of these for the regex lists instead)
+  check <domain>      Show whether a domain is blocked and which list entries
+                      match it
+  tail [--client <text>] [--domain <text>] [--blocked|--allowed] [--interval <duration>]
+                      Follow the query log of the (first) pihole until Ctrl+C,
+                      showing only the queries that match all the filters
+                      (--json for one object per line)
+  gravity             Update gravity (download the blocklists again), showing
+                      its output as it runs (Pi-hole v6 only)
+  auth set [name]     Store the api key of a pihole in the system keyring
+                      (name can be left out when only one pihole is configured)
+  history [--csv] [--limit <n>]
+                      Show who enabled/disabled blocking when, oldest first
+                      (--csv or --json to export, --limit for only the last n)
+
+Options:
+  --config <path>     Use this config file instead of the default one
+  --json              Print the result as JSON
+
+Exit codes:
+  0  Success (status: blocking is enabled)
+  1  status: blocking is disabled on some or all piholes,
+     check: the domain is blocked
+  2  Bad arguments or config
+  3  Pihole unreachable or timed out
+  4  Api key rejected
+  5  Any other error";
+
+/// Exit codes, see USAGE
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_DISABLED: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_UNREACHABLE: i32 = 3;
+pub const EXIT_AUTH: i32 = 4;
+pub const EXIT_OTHER: i32 = 5;
+
+/// Parsed command line
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Value of --config
+    pub config: Option<PathBuf>,
+    /// Print JSON instead of text
+    pub json: bool,
+    /// Command and its arguments, empty to start the tray
+    pub command: Vec<String>,
+}
+
+/// Commands that act on the piholes. These are forwarded to the tray when it is running (unless --config is given)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Status,
+    Enable,
+    /// Disable for n seconds, 0 for until enabled again
+    Disable(u64),
+    Toggle,
+}
+
+/// What a command printed and how it ended. A running tray sends this back to the forwarding process
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Reply {
+    fn ok(code: i32, stdout: impl Into<String>) -> Self {
+        Self {code, stdout: stdout.into(), stderr: String::new()}
+    }
+
+    // Errors go to stderr, or to stdout as {"error": ...} when JSON was asked for
+    fn failed(failure: Failure, json: bool) -> Self {
+        if json {
+            Self {code: failure.code, stdout: json!({"error": failure.message}).to_string(), stderr: String::new()}
+        } else {
+            Self {code: failure.code, stdout: String::new(), stderr: failure.message}
+        }
+    }
+
+    /// Print the output like the command ran here
+    pub fn print(&self) {
+        if !self.stdout.is_empty() {
+            println!("{}", self.stdout);
+        }
+        if !self.stderr.is_empty() {
+            eprintln!("{}", self.stderr);
+        }
+    }
+}
+
+// Why a command failed and which exit code that is
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {code, message: message.into()}
+    }
+}
+
+impl From<PiHoleError> for Failure {
+    fn from(e: PiHoleError) -> Self {
+        let code = match e {
+            PiHoleError::Unreachable(_) | PiHoleError::Timeout(_) => EXIT_UNREACHABLE,
+            PiHoleError::AuthRejected(_) => EXIT_AUTH,
+            PiHoleError::UnexpectedPayload(_) | PiHoleError::Refused(_) => EXIT_OTHER,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+/// Split the arguments (without the program name) into the options and the command
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let path = args.next().ok_or("--config needs a path".to_string())?;
+            parsed.config = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            parsed.config = Some(PathBuf::from(path));
+        } else if arg == "--json" {
+            parsed.json = true;
+        } else if arg == "--help" || arg == "-h" {
+            parsed.command = vec!["help".to_string()];
+            return Ok(parsed);
+        } else {
+            parsed.command.push(arg);
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Read an action command ("status", "disable 5m", ...). Ok(None) when it is some other command
+pub fn parse_action(command: &[String]) -> Result<Option<Action>, String> {
+    let command: Vec<&str> = command.iter().map(String::as_str).collect();
+
+    Ok(Some(match command.as_slice() {
+        ["status"] => Action::Status,
+        ["enable"] => Action::Enable,
+        ["disable"] => Action::Disable(0),
+        ["disable", duration] => Action::Disable(parse_duration(duration).map_err(|e| format!("Invalid duration: {}", e))?),
+        ["toggle"] => Action::Toggle,
+        _ => return Ok(None),
+    }))
+}
+
+/// Run a command and return the exit code for it
+pub fn run(args: &Args) -> i32 {
+    let command: Vec<&str> = args.command.iter().map(String::as_str).collect();
+
+    let reply = match command.as_slice() {
+        ["help"] => Reply::ok(EXIT_OK, USAGE),
+        ["auth", "set"] => auth_set(args, None),
+        ["auth", "set", name] => auth_set(args, Some(name)),
+        ["history", options @ ..] => match show_history(options, args.json) {
+            Ok(reply) => reply,
+            Err(failure) => Reply::failed(failure, args.json),
+        },
+        ["check", domain] => match check(args, domain) {
+            Ok(reply) => reply,
+            Err(failure) => Reply::failed(failure, args.json),
+        },
+        ["gravity"] => match gravity(args) {
+            Ok(reply) => reply,
+            Err(failure) => Reply::failed(failure, args.json),
+        },
+        ["tail", options @ ..] => match tail(args, options) {
+            Ok(reply) => reply,
+            Err(failure) => Reply::failed(failure, args.json),
+        },
+        ["domains" | "allow" | "block" | "unallow" | "unblock", ..] => match domains(args, &command) {
+            Ok(reply) => reply,
+            Err(failure) => Reply::failed(failure, args.json),
+        },
+        _ => match parse_action(&args.command) {
+            Ok(Some(action)) => match connect(args) {
+                Ok(group) => {
+                    let reply = execute(&group, action, args.json);
+                    disconnect(&group);
+                    reply
+                }
+                Err(failure) => Reply::failed(failure, args.json),
+            },
+            Ok(None) => Reply {
+                code: EXIT_USAGE,
+                stdout: String::new(),
+                stderr: format!("Unknown command: {}\n\n{}", args.command.join(" "), USAGE),
+            },
+            Err(e) => Reply::failed(Failure::new(EXIT_USAGE, e), args.json),
+        },
+    };
+
+    reply.print();
+    reply.code
+}
+
+/// Run an action against the piholes and describe the outcome
+pub fn execute<B: PiHoleBackend>(group: &PiHoleGroup<B>, action: Action, json: bool) -> Reply {
+    let result = match action {
+        Action::Status => status(group, json),
+        Action::Enable => describe_action(block_on!(tray_functions::enable(group)), json),
+        Action::Disable(seconds) => describe_action(block_on!(tray_functions::disable(group, seconds)), json),
+        Action::Toggle => describe_action(block_on!(tray_functions::toggle(group)), json),
+    };
+
+    result.unwrap_or_else(|failure| Reply::failed(failure, json))
+}
+
+// Load the config and connect to the piholes in it
+fn connect(args: &Args) -> Result<PiHoleGroup<AuthPiHoleAPI>, Failure> {
+    let config = config::load(args.config.as_deref()).map_err(|e| Failure::new(EXIT_USAGE, e))?;
+    logging::use_config_level(config.log_level.as_deref());
+
+    PiHoleGroup::from_config(config.piholes, credentials::default_store().as_ref())
+        .map_err(|e| Failure::new(EXIT_USAGE, e))
+}
+
+// Free the v6 sessions again, every run would otherwise take up a session slot on the pihole
+fn disconnect(group: &PiHoleGroup<AuthPiHoleAPI>) {
+    for instance in group.instances() {
+        block_on!(instance.api.logout());
+    }
+}
+
+// Describe the status of every pihole, the exit code tells if blocking is on
+fn status<B: PiHoleBackend>(group: &PiHoleGroup<B>, json: bool) -> Result<Reply, Failure> {
+    let statuses = block_on!(group.statuses());
+    let state = GroupState::aggregate(&statuses);
+
+    // Nothing answered, report it like any other failed request
+    if state == GroupState::Unreachable {
+        let (instance, error) = group.instances().iter()
+            .zip(statuses)
+            .find_map(|(instance, status)| status.err().map(|e| (instance, e)))
+            .unwrap();
+        return Err(error.for_host(&instance.name).into());
+    }
+
+    let timer = statuses.iter().flatten().filter_map(|status| status.timer_remaining).max();
+    let code = if state == GroupState::Enabled {EXIT_OK} else {EXIT_DISABLED};
+
+    if json {
+        let piholes: Vec<_> = group.instances().iter().zip(&statuses).map(|(instance, status)| match status {
+            Ok(status) => json!({
+                "name": instance.name,
+                "enabled": status.enabled,
+                "timer_remaining": status.timer_remaining,
+            }),
+            Err(e) => json!({"name": instance.name, "error": e.to_string()}),
+        }).collect();
+
+        return Ok(Reply::ok(code, json!({
+            "state": state.as_str(),
+            "enabled": state == GroupState::Enabled,
+            "timer_remaining": timer,
+            "piholes": piholes,
+        }).to_string()));
+    }
+
+    let mut lines = vec![describe_status(state == GroupState::Enabled, state == GroupState::Mixed, timer)];
+
+    // Only list the piholes one by one when there is more than one
+    if statuses.len() > 1 {
+        for (instance, status) in group.instances().iter().zip(&statuses) {
+            match status {
+                Ok(status) => lines.push(format!("  {}: {}", instance.name, if status.enabled {"enabled"} else {"disabled"})),
+                Err(e) => lines.push(format!("  {}: {}", instance.name, e)),
+            }
+        }
+    }
+
+    Ok(Reply::ok(code, lines.join("\n")))
+}
+
+// Describe the new status after enable/disable/toggle
+fn describe_action(result: Result<BlockingStatus, PiHoleError>, json: bool) -> Result<Reply, Failure> {
+    let status = result?;
+
+    if json {
+        return Ok(Reply::ok(EXIT_OK, json!({
+            "state": if status.enabled {"enabled"} else {"disabled"},
+            "enabled": status.enabled,
+            "timer_remaining": status.timer_remaining,
+        }).to_string()));
+    }
+
+    Ok(Reply::ok(EXIT_OK, describe_status(status.enabled, false, status.timer_remaining)))
+}
+
+// One line summary like "Blocking disabled for 5 minutes"
+fn describe_status(enabled: bool, mixed: bool, timer: Option<u64>) -> String {
+    if enabled {
+        return "Blocking enabled".to_string();
+    }
+
+    let text = if mixed {"Blocking partly disabled"} else {"Blocking disabled"};
+    match timer {
+        Some(seconds) if seconds > 0 => format!("{} for {}", text, describe_duration(seconds)),
+        _ => text.to_string(),
+    }
+}
+
+// Say whether a domain is blocked and why, the exit code tells which
+fn check(args: &Args, domain: &str) -> Result<Reply, Failure> {
+    // A link works as well as a domain
+    let domain = tray_functions::domain_from_text(domain)
+        .ok_or(Failure::new(EXIT_USAGE, format!("\"{}\" is not a domain", domain)))?;
+
+    let group = connect(args)?;
+    let result = block_on!(tray_functions::check_domain(&group, &domain));
+    disconnect(&group);
+    let result = result?;
+
+    let code = if result.blocked() {EXIT_DISABLED} else {EXIT_OK};
+    if args.json {
+        let matches: Vec<_> = result.matches.iter().map(|found| json!({
+            "source": found.source.describe(),
+            "allows": found.source.allows(),
+            "entry": found.entry,
+            "enabled": found.enabled,
+        })).collect();
+        return Ok(Reply::ok(code, json!({"domain": result.domain, "blocked": result.blocked(), "matches": matches}).to_string()));
+    }
+
+    Ok(Reply::ok(code, tray_functions::describe_search(&result)))
+}
+
+// Print the queries of the query log as they come in, until Ctrl+C
+fn tail(args: &Args, options: &[&str]) -> Result<Reply, Failure> {
+    let mut filter = querylog::Filter::default();
+    let mut interval = querylog::FOLLOW_INTERVAL;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().copied().ok_or(Failure::new(EXIT_USAGE, format!("{} needs a value", option)));
+        match *option {
+            "--client" => filter.client = value()?.to_string(),
+            "--domain" => filter.domain = value()?.to_string(),
+            "--blocked" => filter.blocked = Some(true),
+            "--allowed" => filter.blocked = Some(false),
+            "--interval" => {
+                let seconds = parse_duration(value()?).map_err(|e| Failure::new(EXIT_USAGE, format!("Invalid interval: {}", e)))?;
+                interval = std::time::Duration::from_secs(seconds);
+            }
+            other => return Err(Failure::new(EXIT_USAGE, format!("Unknown tail option: {}", other))),
+        }
+    }
+
+    let group = connect(args)?;
+    let result = block_on!(async {
+        tokio::select! {
+            result = follow_queries(&group, &filter, interval, args.json) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    });
+    disconnect(&group);
+    result?;
+
+    // Everything was printed along the way
+    Ok(Reply::ok(EXIT_OK, ""))
+}
+
+// Keep reading the query log. Only the first read failing ends it, after that the pihole gets another chance every interval
+async fn follow_queries<B: PiHoleBackend>(group: &PiHoleGroup<B>, filter: &querylog::Filter, interval: std::time::Duration, json: bool) -> Result<(), Failure> {
+    let mut cursor = None;
+    loop {
+        match group.queries(cursor.as_ref()).await {
+            Ok((queries, next)) => {
+                cursor = Some(next);
+                for query in queries.iter().filter(|query| filter.matches(query)) {
+                    let line = if json {serde_json::to_string(query).unwrap()} else {querylog::describe(query)};
+                    // Stop quietly when whatever reads the output is gone (ex. piped into head)
+                    if writeln!(std::io::stdout(), "{}", line).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) if cursor.is_none() => return Err(e.into()),
+            Err(e) => eprintln!("{}", e),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Update gravity, printing its output along the way
+fn gravity(args: &Args) -> Result<Reply, Failure> {
+    let json = args.json;
+    let progress = move |line: &str| {
+        // Like tail, --json gives one object per line
+        if json {
+            println!("{}", json!({"output": line}));
+        } else {
+            println!("{}", line);
+        }
+    };
+
+    let group = connect(args)?;
+    let result = block_on!(async {
+        tokio::select! {
+            result = tray_functions::update_gravity(&group, &progress) => Some(result),
+            _ = tokio::signal::ctrl_c() => None,
+        }
+    });
+    disconnect(&group);
+
+    match result {
+        Some(result) => result?,
+        // The pihole carries on either way, there's just nobody watching
+        None => return Err(Failure::new(EXIT_OTHER, "Stopped watching, gravity keeps updating on the pihole")),
+    }
+
+    if json {
+        return Ok(Reply::ok(EXIT_OK, json!({"updated": true}).to_string()));
+    }
+    Ok(Reply::ok(EXIT_OK, "Gravity updated"))
+}
+
+// List, add or remove allow/deny list domains
+fn domains(args: &Args, command: &[&str]) -> Result<Reply, Failure> {
+    let regex = command.contains(&"--regex");
+
+    // Pick out --for <duration>, the rest is the command itself
+    let mut seconds = None;
+    let mut words = Vec::new();
+    let mut rest = command.iter().copied();
+    while let Some(word) = rest.next() {
+        match word {
+            "--regex" => {}
+            "--for" => {
+                let text = rest.next().ok_or(Failure::new(EXIT_USAGE, "--for needs a duration"))?;
+                seconds = Some(parse_duration(text).map_err(|e| Failure::new(EXIT_USAGE, format!("Invalid duration: {}", e)))?);
+            }
+            word => words.push(word),
+        }
+    }
+    if seconds.is_some() && (words[0] != "allow" || regex) {
+        return Err(Failure::new(EXIT_USAGE, "--for only works with allow, for exact domains"));
+    }
+
+    // Work out what to do before connecting, so a typo doesn't wait on the piholes
+    let lists = match words.as_slice() {
+        ["domains"] if regex => vec![DomainList::AllowRegex, DomainList::DenyRegex],
+        ["domains"] => DomainList::ALL.to_vec(),
+        ["domains", "allow"] => vec![DomainList::new(true, regex)],
+        ["domains", "deny"] => vec![DomainList::new(false, regex)],
+        ["allow" | "unallow", _] => vec![DomainList::new(true, regex)],
+        ["block" | "unblock", _] => vec![DomainList::new(false, regex)],
+        _ => return Err(Failure::new(EXIT_USAGE, format!("Unknown command: {}\n\n{}", command.join(" "), USAGE))),
+    };
+
+    // Exact domains can be given as a link, like in the tray. Regexes are used as they are
+    let domain = match words.as_slice() {
+        [_, domain] if !regex && words[0] != "domains" => Some(tray_functions::domain_from_text(domain)
+            .ok_or(Failure::new(EXIT_USAGE, format!("\"{}\" is not a domain", domain)))?),
+        [_, domain] => Some(domain.to_string()),
+        _ => None,
+    };
+
+    let group = connect(args)?;
+
+    // Catch up on temporary allows that ran out while the tray wasn't running
+    block_on!(temporary::expire_due(&group));
+
+    let result = match (words[0], domain, seconds) {
+        ("allow", Some(domain), Some(seconds)) => block_on!(tray_functions::allow_domain_for(&group, &domain, seconds))
+            .map(|_| allowed_for(&domain, seconds, args.json))
+            .map_err(Failure::from),
+        ("allow" | "block", Some(domain), _) => block_on!(tray_functions::add_domain(&group, lists[0], &domain))
+            .map(|_| describe_change("added", lists[0], &domain, args.json))
+            .map_err(Failure::from),
+        ("unallow" | "unblock", Some(domain), _) => block_on!(tray_functions::remove_domain(&group, lists[0], &domain))
+            .map(|_| describe_change("removed", lists[0], &domain, args.json))
+            .map_err(Failure::from),
+        _ => list_domains(&group, &lists, args.json),
+    };
+    disconnect(&group);
+    result
+}
+
+// "Added example.com to the allowlist", or {"added": "example.com", "list": "allow"}
+fn describe_change(change: &str, list: DomainList, domain: &str, json: bool) -> Reply {
+    if json {
+        return Reply::ok(EXIT_OK, json!({change: domain, "list": list.as_str()}).to_string());
+    }
+
+    let text = match change {
+        "added" => format!("Added {} to the {}", domain, list.describe()),
+        _ => format!("Removed {} from the {}", domain, list.describe()),
+    };
+    Reply::ok(EXIT_OK, text)
+}
+
+// "Allowed example.com for 15 minutes", or {"added": "example.com", "list": "allow", "seconds": 900}
+fn allowed_for(domain: &str, seconds: u64, json: bool) -> Reply {
+    if json {
+        return Reply::ok(EXIT_OK, json!({"added": domain, "list": DomainList::Allow.as_str(), "seconds": seconds}).to_string());
+    }
+
+    Reply::ok(EXIT_OK, format!("Allowed {} for {}", domain, describe_duration(seconds)))
+}
+
+// Print the given lists, each under its own heading
+fn list_domains<B: PiHoleBackend>(group: &PiHoleGroup<B>, lists: &[DomainList], json: bool) -> Result<Reply, Failure> {
+    let mut found = Vec::new();
+    for list in lists {
+        found.push((*list, block_on!(group.domains(*list))?));
+    }
+
+    // Temporary allows say when they end
+    let expires = |list: &DomainList, domain: &str| match list {
+        DomainList::Allow => temporary::expires(domain, group),
+        _ => None,
+    };
+
+    if json {
+        let lists: serde_json::Map<String, serde_json::Value> = found.iter()
+            .map(|(list, domains)| {
+                let domains: Vec<_> = domains.iter().map(|entry| {
+                    let mut value = json!(entry);
+                    if let Some(time) = expires(list, &entry.domain) {
+                        value["expires"] = json!(time.to_rfc3339());
+                    }
+                    value
+                }).collect();
+                (list.as_str().to_string(), json!(domains))
+            })
+            .collect();
+        return Ok(Reply::ok(EXIT_OK, serde_json::Value::Object(lists).to_string()));
+    }
+
+    let mut lines = Vec::new();
+    for (list, domains) in found.iter().filter(|(_, domains)| !domains.is_empty()) {
+        // Upper case first letter for the heading
+        let heading = list.describe();
+        lines.push(format!("{}{}:", heading[..1].to_uppercase(), &heading[1..]));
+        for entry in domains {
+            let mut line = format!("  {}", entry.domain);
+            if !entry.enabled {
+                line.push_str(" (disabled)");
+            }
+            if let Some(time) = expires(list, &entry.domain) {
+                line.push_str(&format!(" (until {})", time.format("%H:%M")));
+            }
+            if let Some(comment) = &entry.comment {
+                line.push_str(&format!("  # {}", comment));
+            }
+            lines.push(line);
+        }
+    }
+    if lines.is_empty() {
+        return Ok(Reply::ok(EXIT_OK, "No domains on the lists"));
+    }
+
+    Ok(Reply::ok(EXIT_OK, lines.join("\n")))
+}
+
+// Print the action history as text, CSV or JSON
+fn show_history(options: &[&str], json: bool) -> Result<Reply, Failure> {
+    let mut csv = false;
+    let mut limit = None;
+
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--csv" => csv = true,
+            "--limit" => {
+                let count = options.next().and_then(|count| count.parse::<usize>().ok())
+                    .ok_or(Failure::new(EXIT_USAGE, "--limit needs a number"))?;
+                limit = Some(count);
+            }
+            other => return Err(Failure::new(EXIT_USAGE, format!("Unknown history option: {}", other))),
+        }
+    }
+    if csv && json {
+        return Err(Failure::new(EXIT_USAGE, "Pick one of --csv and --json"));
+    }
+
+    let mut entries = history::read().map_err(|e| Failure::new(EXIT_OTHER, e))?;
+    if let Some(limit) = limit {
+        entries = entries.split_off(entries.len().saturating_sub(limit));
+    }
+
+    if json {
+        return Ok(Reply::ok(EXIT_OK, serde_json::to_string(&entries).unwrap()));
+    }
+    if csv {
+        return Ok(Reply::ok(EXIT_OK, history::to_csv(&entries)));
+    }
+    if entries.is_empty() {
+        return Ok(Reply::ok(EXIT_OK, "No actions recorded yet"));
+    }
+
+    let lines: Vec<String> = entries.iter()
+        .map(|entry| format!("{}  {}  {}  {}  {}", entry.timestamp, entry.user, entry.describe_action(), entry.host, entry.result))
+        .collect();
+    Ok(Reply::ok(EXIT_OK, lines.join("\n")))
+}
+
+// Ask for the key of a pihole and save it in the keyring
+fn auth_set(args: &Args, name: Option<&str>) -> Reply {
+    match store_key(args, name) {
+        Ok(name) if args.json => Reply::ok(EXIT_OK, json!({"saved": name}).to_string()),
+        Ok(name) => Reply::ok(EXIT_OK, format!("Key for \"{}\" saved", name)),
+        Err(failure) => Reply::failed(failure, args.json),
+    }
+}
+
+// Returns the name the key was saved under
+fn store_key(args: &Args, name: Option<&str>) -> Result<String, Failure> {
+    let config = config::load(args.config.as_deref()).map_err(|e| Failure::new(EXIT_USAGE, e))?;
+
+    // Only names from the config are accepted so a typo doesn't store a key nothing will read
+    let names: Vec<&str> = config.piholes.iter().map(|pihole| pihole.name.as_str()).collect();
+    let name = match name {
+        Some(name) if names.contains(&name) => name,
+        Some(name) => return Err(Failure::new(EXIT_USAGE, format!("No pihole named \"{}\" in the config (configured: {})", name, names.join(", ")))),
+        None if names.len() == 1 => names[0],
+        None => return Err(Failure::new(EXIT_USAGE, format!("Several piholes are configured, pick one: {}", names.join(", ")))),
+    };
+
+    let key = read_key(&format!("Api key for {}: ", name)).map_err(|e| Failure::new(EXIT_OTHER, e))?;
+
+    credentials::default_store().set(name, &key).map_err(|e| Failure::new(EXIT_OTHER, e))?;
+    Ok(name.to_string())
+}
+
+// Read the key without echoing it, or a plain line when the key is piped in
+fn read_key(prompt: &str) -> Result<String, String> {
+    let key = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt).map_err(|e| e.to_string())?
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line).map_err(|e| e.to_string())?;
+        line
+    };
+
+    Ok(key.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakePiHole;
+    use crate::pihole_group::Instance;
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn group(piholes: &[(&str, bool)]) -> (PiHoleGroup<FakePiHole>, Vec<FakePiHole>) {
+        let fakes: Vec<FakePiHole> = piholes.iter().map(|(_, enabled)| FakePiHole::new(*enabled)).collect();
+        let group = PiHoleGroup::new(piholes.iter().zip(&fakes)
+            .map(|((name, _), fake)| Instance {name: name.to_string(), api: fake.clone()})
+            .collect());
+        (group, fakes)
+    }
+
+    #[test]
+    fn options_and_command() {
+        let args = parse_args(words("--config /tmp/pihole.toml --json disable 5m")).unwrap();
+        assert_eq!(args, Args {config: Some(PathBuf::from("/tmp/pihole.toml")), json: true, command: words("disable 5m")});
+
+        let args = parse_args(words("status --config=/tmp/pihole.toml")).unwrap();
+        assert_eq!(args.config, Some(PathBuf::from("/tmp/pihole.toml")));
+        assert_eq!(args.command, words("status"));
+
+        // --help wins over anything else
+        assert_eq!(parse_args(words("disable --help 5m")).unwrap().command, words("help"));
+        assert_eq!(parse_args(words("")).unwrap(), Args::default());
+        assert!(parse_args(words("status --config")).is_err());
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(parse_action(&words("status")), Ok(Some(Action::Status)));
+        assert_eq!(parse_action(&words("enable")), Ok(Some(Action::Enable)));
+        assert_eq!(parse_action(&words("disable")), Ok(Some(Action::Disable(0))));
+        assert_eq!(parse_action(&words("disable 1h30m")), Ok(Some(Action::Disable(5400))));
+        assert_eq!(parse_action(&words("toggle")), Ok(Some(Action::Toggle)));
+
+        // Missing or invalid durations
+        assert!(parse_action(&words("disable soon")).is_err());
+        assert!(parse_action(&words("disable 0")).is_err());
+        assert!(parse_action(&words("disable 99999999999d")).is_err());
+
+        // Unknown commands and extra arguments aren't actions
+        assert_eq!(parse_action(&words("pause")), Ok(None));
+        assert_eq!(parse_action(&words("status now")), Ok(None));
+        assert_eq!(parse_action(&words("disable 5m 10m")), Ok(None));
+        assert_eq!(parse_action(&words("")), Ok(None));
+    }
+
+    #[test]
+    fn bad_commands_exit_with_usage() {
+        for command in ["pause", "status now", "disable 5m 10m", "disable soon", "allow", "allow example.com --for", "block example.com --for 5m"] {
+            let args = Args {command: words(command), ..Args::default()};
+            assert_eq!(run(&args), EXIT_USAGE, "{}", command);
+        }
+    }
+
+    #[test]
+    fn status_output() {
+        let (group, fakes) = group(&[("primary", true)]);
+        assert_eq!(execute(&group, Action::Status, false), Reply::ok(EXIT_OK, "Blocking enabled"));
+
+        fakes[0].set_enabled(false);
+        assert_eq!(execute(&group, Action::Status, false), Reply::ok(EXIT_DISABLED, "Blocking disabled"));
+
+        let reply = execute(&group, Action::Status, true);
+        assert_eq!(reply.code, EXIT_DISABLED);
+        let value: serde_json::Value = serde_json::from_str(&reply.stdout).unwrap();
+        assert_eq!(value["state"], "disabled");
+        assert_eq!(value["piholes"][0]["name"], "primary");
+    }
+
+    #[test]
+    fn status_of_several() {
+        let (group, fakes) = group(&[("primary", true), ("secondary", false)]);
+        let reply = execute(&group, Action::Status, false);
+        assert_eq!(reply, Reply::ok(EXIT_DISABLED, "Blocking partly disabled\n  primary: enabled\n  secondary: disabled"));
+
+        // One down is still a status, it's listed with the error
+        fakes[1].set_unreachable(true);
+        let reply = execute(&group, Action::Status, false);
+        assert_eq!(reply.code, EXIT_OK);
+        assert!(reply.stdout.contains("secondary: Pihole unreachable"), "{}", reply.stdout);
+    }
+
+    #[test]
+    fn actions_output() {
+        let (group, fakes) = group(&[("primary", true)]);
+
+        assert_eq!(execute(&group, Action::Disable(300), false), Reply::ok(EXIT_OK, "Blocking disabled for 5 minutes"));
+        assert_eq!(fakes[0].timer(), Some(300));
+        assert_eq!(execute(&group, Action::Enable, false), Reply::ok(EXIT_OK, "Blocking enabled"));
+        assert_eq!(execute(&group, Action::Toggle, false), Reply::ok(EXIT_OK, "Blocking disabled"));
+        assert!(!fakes[0].is_enabled());
+
+        let reply = execute(&group, Action::Toggle, true);
+        assert_eq!(reply.code, EXIT_OK);
+        let value: serde_json::Value = serde_json::from_str(&reply.stdout).unwrap();
+        assert_eq!(value["enabled"], true);
+    }
+
+    #[test]
+    fn unreachable() {
+        let (group, fakes) = group(&[("primary", true)]);
+        fakes[0].set_unreachable(true);
+
+        for action in [Action::Status, Action::Enable, Action::Disable(60), Action::Toggle] {
+            let reply = execute(&group, action, false);
+            assert_eq!(reply.code, EXIT_UNREACHABLE, "{:?}", action);
+            assert!(reply.stdout.is_empty());
+            assert!(reply.stderr.starts_with("Pihole unreachable: primary: "), "{}", reply.stderr);
+        }
+
+        // With --json the error is on stdout instead
+        let reply = execute(&group, Action::Status, true);
+        assert_eq!(reply.code, EXIT_UNREACHABLE);
+        assert!(reply.stderr.is_empty());
+        let value: serde_json::Value = serde_json::from_str(&reply.stdout).unwrap();
+        assert!(value["error"].as_str().unwrap().starts_with("Pihole unreachable"));
+    }
+}
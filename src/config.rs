@@ -0,0 +1,384 @@
+/*
+    Settings for the app.
+    Read from a TOML file ($XDG_CONFIG_HOME/pihole-switch/config.toml, or the path given with --config).
+    When there is no file the PI_HOLE_* environment variables are used instead
+ */
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::tray_functions::{describe_duration, parse_duration};
+
+/// A "Disable ..." entry in the tray menu
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preset {
+    pub label: String,
+    pub seconds: u64,
+}
+
+impl Preset {
+    /// Create a preset with a generated label ("Disable 5 minutes")
+    pub fn new(seconds: u64) -> Self {
+        Self {label: format!("Disable {}", describe_duration(seconds)), seconds}
+    }
+}
+
+/// Connection details of a single pihole
+#[derive(Clone, Debug, PartialEq)]
+pub struct PiHoleConfig {
+    pub name: String,
+    pub address: String,
+    /// Plaintext key from the config or PI_HOLE_KEY, only used when the keyring has none
+    pub key: Option<String>,
+}
+
+/// Which state changes pop up a desktop notification (linux only)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    /// Blocking came back on, ex. after a timed disable ran out
+    pub resumed: bool,
+    /// Blocking was turned off somewhere else, ex. in the web interface
+    pub disabled: bool,
+    /// The piholes stopped answering
+    pub unreachable: bool,
+    /// The piholes answer again
+    pub reachable: bool,
+    /// A gravity update from the tray finished or failed
+    pub gravity: bool,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {resumed: true, disabled: true, unreachable: true, reachable: true, gravity: true}
+    }
+}
+
+/// Validated settings
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub piholes: Vec<PiHoleConfig>,
+    pub presets: Vec<Preset>,
+    /// Seconds between status checks while the piholes answer
+    pub poll_interval: u64,
+    pub notifications: Notifications,
+    /// Log level ("debug", "warn", ...), None for the default (see logging.rs)
+    pub log_level: Option<String>,
+}
+
+// Layout of config.toml
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default, rename = "pihole")]
+    piholes: Vec<PiHoleEntry>,
+    #[serde(rename = "preset")]
+    presets: Option<Vec<PresetEntry>>,
+    poll_interval: Option<String>,
+    log_level: Option<String>,
+    #[serde(default)]
+    notifications: Notifications,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PiHoleEntry {
+    name: Option<String>,
+    address: String,
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetEntry {
+    label: Option<String>,
+    duration: String,
+}
+
+/// Seconds between status checks when none is configured
+pub const DEFAULT_POLL_INTERVAL: u64 = 1;
+
+/// Presets shown when none are configured
+pub fn default_presets() -> Vec<Preset> {
+    vec![Preset::new(10), Preset::new(30), Preset::new(60 * 5)]
+}
+
+/// Where the config file is looked for when --config isn't given
+pub fn default_path() -> Option<PathBuf> {
+    // $XDG_CONFIG_HOME (or ~/.config) on linux, %APPDATA% on windows
+    dirs::config_dir().map(|dir| dir.join("pihole-switch").join("config.toml"))
+}
+
+/// Where the log and the action history are kept
+pub fn state_dir() -> Option<PathBuf> {
+    // Tests keep their history and temporary allows out of the real folder
+    if cfg!(test) {
+        return Some(std::env::temp_dir().join(format!("pihole-switch-test-{}", std::process::id())));
+    }
+
+    // $XDG_STATE_HOME (or ~/.local/state) on linux, %LOCALAPPDATA% on windows
+    dirs::state_dir().or_else(dirs::data_local_dir).map(|dir| dir.join("pihole-switch"))
+}
+
+/// Load the settings. An explicit path must exist, otherwise the default path is tried
+/// and the environment variables are used when there is no file there
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    if let Some(path) = path {
+        return from_file(path);
+    }
+
+    match default_path() {
+        Some(path) if path.exists() => from_file(&path),
+        _ => from_env().map_err(|e| format!(
+            "{}\nCreate {} or set the PI_HOLE_ADDR environment variable",
+            e,
+            default_path().map(|path| path.display().to_string()).unwrap_or("a config file".to_string()),
+        )),
+    }
+}
+
+/// Read and validate a config file
+pub fn from_file(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+
+    parse(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// Parse and validate the contents of a config file
+pub fn parse(text: &str) -> Result<Config, String> {
+    let file: ConfigFile = toml::from_str(text).map_err(|e| e.to_string())?;
+
+    let piholes = file.piholes.into_iter()
+        .map(|entry| PiHoleConfig {
+            name: entry.name.unwrap_or_else(|| default_name(&entry.address)),
+            address: entry.address,
+            key: entry.key,
+        })
+        .collect();
+
+    let presets = match file.presets {
+        Some(entries) => entries.into_iter()
+            .map(|entry| {
+                let seconds = parse_duration(&entry.duration)
+                    .map_err(|e| format!("Preset \"{}\": {}", entry.duration, e))?;
+                Ok(match entry.label {
+                    Some(label) => Preset {label, seconds},
+                    None => Preset::new(seconds),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?,
+        None => default_presets(),
+    };
+
+    let poll_interval = match file.poll_interval {
+        Some(value) => parse_duration(&value).map_err(|e| format!("poll_interval \"{}\": {}", value, e))?,
+        None => DEFAULT_POLL_INTERVAL,
+    };
+
+    validate(Config {piholes, presets, poll_interval, notifications: file.notifications, log_level: file.log_level})
+}
+
+/// Build the settings from the PI_HOLE_* environment variables.
+/// Several piholes are given as comma separated lists
+pub fn from_env() -> Result<Config, String> {
+    let addrs = split_list(&std::env::var("PI_HOLE_ADDR").map_err(|_| "PI_HOLE_ADDR is not set".to_string())?);
+    // Keys are optional here, they can come from the keyring instead
+    let keys = split_list(&std::env::var("PI_HOLE_KEY").unwrap_or_default());
+    let names = split_list(&std::env::var("PI_HOLE_NAME").unwrap_or_default());
+
+    // One key can be shared by every pihole
+    if keys.len() > 1 && keys.len() != addrs.len() {
+        return Err("PI_HOLE_KEY must have one key or one key per address".to_string());
+    }
+
+    let piholes = addrs.iter().enumerate().map(|(i, addr)| PiHoleConfig {
+        name: names.get(i).cloned().unwrap_or_else(|| default_name(addr)),
+        address: addr.clone(),
+        key: keys.get(i).or(keys.first()).cloned(),
+    }).collect();
+
+    // Disable buttons for the menu, ex. PI_HOLE_PRESETS=10s,30s,Lunch=1h
+    let presets = match std::env::var("PI_HOLE_PRESETS") {
+        Ok(value) => parse_presets(&value).map_err(|e| format!("Invalid PI_HOLE_PRESETS: {}", e))?,
+        Err(_) => default_presets(),
+    };
+
+    // Time between status checks, ex. PI_HOLE_POLL_INTERVAL=5s
+    let poll_interval = match std::env::var("PI_HOLE_POLL_INTERVAL") {
+        Ok(value) => parse_duration(&value).map_err(|e| format!("Invalid PI_HOLE_POLL_INTERVAL: {}", e))?,
+        Err(_) => DEFAULT_POLL_INTERVAL,
+    };
+
+    // Events to notify about, ex. PI_HOLE_NOTIFY=resumed,unreachable or PI_HOLE_NOTIFY=none
+    let notifications = match std::env::var("PI_HOLE_NOTIFY") {
+        Ok(value) => parse_notifications(&value).map_err(|e| format!("Invalid PI_HOLE_NOTIFY: {}", e))?,
+        Err(_) => Notifications::default(),
+    };
+
+    validate(Config {piholes, presets, poll_interval, notifications, log_level: None})
+}
+
+/// Parse a comma separated list of the events to notify about ("resumed,unreachable").
+/// "none" turns every notification off
+pub fn parse_notifications(value: &str) -> Result<Notifications, String> {
+    let mut notifications = Notifications {resumed: false, disabled: false, unreachable: false, reachable: false, gravity: false};
+
+    for event in value.split(',').map(str::trim).filter(|event| !event.is_empty()) {
+        match event {
+            "resumed" => notifications.resumed = true,
+            "disabled" => notifications.disabled = true,
+            "unreachable" => notifications.unreachable = true,
+            "reachable" => notifications.reachable = true,
+            "gravity" => notifications.gravity = true,
+            "none" => {}
+            _ => return Err(format!("Unknown event \"{}\" (expected resumed, disabled, unreachable, reachable, gravity or none)", event)),
+        }
+    }
+
+    Ok(notifications)
+}
+
+/// Parse a comma separated preset list. Each entry is a duration with an optional label:
+/// "10s,30s,5m" or "Quick=30s,Lunch break=1h"
+pub fn parse_presets(value: &str) -> Result<Vec<Preset>, String> {
+    let mut presets = Vec::new();
+
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let preset = match entry.split_once('=') {
+            Some((label, duration)) => Preset {
+                label: label.trim().to_string(),
+                seconds: parse_duration(duration)?,
+            },
+            None => Preset::new(parse_duration(entry)?),
+        };
+        presets.push(preset);
+    }
+
+    if presets.is_empty() {
+        return Err("No presets given".to_string());
+    }
+    Ok(presets)
+}
+
+// Check the settings make sense before anything is started
+fn validate(mut config: Config) -> Result<Config, String> {
+    if config.piholes.is_empty() {
+        return Err("No pihole configured".to_string());
+    }
+
+    for (i, pihole) in config.piholes.iter().enumerate() {
+        if !pihole.address.starts_with("http://") && !pihole.address.starts_with("https://") {
+            return Err(format!("Address of pihole \"{}\" must start with http:// or https:// (got \"{}\")", pihole.name, pihole.address));
+        }
+
+        // Names are used to tell the piholes apart in the menu
+        if config.piholes[..i].iter().any(|other| other.name == pihole.name) {
+            return Err(format!("Pihole name \"{}\" is used more than once", pihole.name));
+        }
+    }
+
+    if config.presets.is_empty() {
+        return Err("At least one preset is needed".to_string());
+    }
+
+    // Urls are built as address + "/api/...", avoid a double slash
+    for pihole in config.piholes.iter_mut() {
+        pihole.address = pihole.address.trim_end_matches('/').to_string();
+    }
+
+    Ok(config)
+}
+
+// Default pihole name: the address without the scheme or a trailing slash
+fn default_name(address: &str) -> String {
+    address.split("://").last().unwrap_or(address).trim_end_matches('/').to_string()
+}
+
+// Split a comma separated env value, ignoring empty entries
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_with_and_without_labels() {
+        let presets = parse_presets("10s, Lunch break=1h,,5m").unwrap();
+        assert_eq!(presets, vec![
+            Preset {label: "Disable 10 seconds".to_string(), seconds: 10},
+            Preset {label: "Lunch break".to_string(), seconds: 60 * 60},
+            Preset {label: "Disable 5 minutes".to_string(), seconds: 5 * 60},
+        ]);
+    }
+
+    #[test]
+    fn bad_presets() {
+        assert!(parse_presets("").is_err());
+        assert!(parse_presets(" , ").is_err());
+        assert!(parse_presets("10s,soon").is_err());
+        assert!(parse_presets("Lunch=").is_err());
+        assert!(parse_presets("0").is_err());
+    }
+
+    #[test]
+    fn parse_full_config() {
+        let config = parse(r#"
+            poll_interval = "5s"
+            log_level = "debug"
+
+            [[pihole]]
+            name = "primary"
+            address = "http://192.168.0.102/"
+            key = "abc"
+
+            [[pihole]]
+            address = "https://pi.hole"
+
+            [[preset]]
+            duration = "15m"
+
+            [[preset]]
+            label = "Lunch break"
+            duration = "1h"
+
+            [notifications]
+            disabled = false
+        "#).unwrap();
+
+        assert_eq!(config.piholes, vec![
+            PiHoleConfig {name: "primary".to_string(), address: "http://192.168.0.102".to_string(), key: Some("abc".to_string())},
+            PiHoleConfig {name: "pi.hole".to_string(), address: "https://pi.hole".to_string(), key: None},
+        ]);
+        assert_eq!(config.presets, vec![Preset::new(15 * 60), Preset {label: "Lunch break".to_string(), seconds: 60 * 60}]);
+        assert_eq!(config.poll_interval, 5);
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert!(!config.notifications.disabled);
+        assert!(config.notifications.resumed);
+    }
+
+    #[test]
+    fn parse_defaults() {
+        let config = parse("[[pihole]]\naddress = \"http://pi.hole\"").unwrap();
+        assert_eq!(config.presets, default_presets());
+        assert_eq!(config.poll_interval, DEFAULT_POLL_INTERVAL);
+        assert_eq!(config.notifications, Notifications::default());
+        assert_eq!(config.log_level, None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        // Nothing configured
+        assert!(parse("").is_err());
+        // Typo in a key
+        assert!(parse("[[pihole]]\nadress = \"http://pi.hole\"").is_err());
+        assert!(parse("[[pihole]]\naddress = \"pi.hole\"").is_err());
+        assert!(parse("[[pihole]]\naddress = \"http://a\"\n[[pihole]]\naddress = \"http://a/\"\nname = \"a\"").is_err());
+        assert!(parse("[[pihole]]\naddress = \"http://pi.hole\"\n[[preset]]\nduration = \"soon\"").is_err());
+        assert!(parse("preset = []\n[[pihole]]\naddress = \"http://pi.hole\"").is_err());
+        assert!(parse("poll_interval = \"0\"\n[[pihole]]\naddress = \"http://pi.hole\"").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+/*
+    Storage for the pihole api keys.
+    Keys are kept in the system keyring (Secret Service/libsecret on linux, Credential Manager on windows),
+    one entry per pihole name. A plaintext key in the config is only used when the keyring has none.
+    Setting PIHOLE_SWITCH_KEYRING_FILE swaps the keyring for a plain file, for tests or machines without a keyring daemon
+ */
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::PathBuf;
+use crate::config::PiHoleConfig;
+use crate::log_warn;
+
+/// Service name the keyring entries are stored under
+pub const SERVICE: &str = "pihole-switch";
+
+/// Somewhere api keys can be saved and looked up by pihole name
+pub trait KeyStore {
+    /// Look up the key of a pihole. Ok(None) when nothing is stored for it
+    fn get(&self, name: &str) -> Result<Option<String>, String>;
+
+    /// Store (or replace) the key of a pihole
+    fn set(&self, name: &str, key: &str) -> Result<(), String>;
+}
+
+/// The keyring of the logged in user
+pub struct SystemKeyring;
+
+impl KeyStore for SystemKeyring {
+    fn get(&self, name: &str) -> Result<Option<String>, String> {
+        let entry = keyring::Entry::new(SERVICE, name).map_err(|e| e.to_string())?;
+
+        match entry.get_password() {
+            Ok(key) => Ok(Some(key)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!("Could not read keyring: {}", e)),
+        }
+    }
+
+    fn set(&self, name: &str, key: &str) -> Result<(), String> {
+        let entry = keyring::Entry::new(SERVICE, name).map_err(|e| e.to_string())?;
+
+        entry.set_password(key).map_err(|e| format!("Could not write to keyring: {}", e))
+    }
+}
+
+/// Keys kept in a TOML file (name = "key"). Stand-in for the keyring, the keys are NOT encrypted
+pub struct FileKeyStore {
+    path: PathBuf,
+}
+
+impl FileKeyStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self {path}
+    }
+
+    // Read every stored key, a missing file is the same as an empty one
+    fn read(&self) -> Result<BTreeMap<String, String>, String> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| format!("Invalid key file {}: {}", self.path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(format!("Could not read key file {}: {}", self.path.display(), e)),
+        }
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn get(&self, name: &str) -> Result<Option<String>, String> {
+        Ok(self.read()?.remove(name))
+    }
+
+    fn set(&self, name: &str, key: &str) -> Result<(), String> {
+        let mut keys = self.read()?;
+        keys.insert(name.to_string(), key.to_string());
+
+        let text = toml::to_string(&keys).map_err(|e| e.to_string())?;
+        let write_error = |e: std::io::Error| format!("Could not write key file {}: {}", self.path.display(), e);
+
+        // Written to a new file only the owner can read, then moved over the old one.
+        // That way the keys are never readable by others, not even for a moment
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        // A leftover from an earlier crash may have looser permissions, so it's never reused
+        let _ = std::fs::remove_file(&temporary);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&temporary).map_err(write_error)?;
+        file.write_all(text.as_bytes()).map_err(write_error)?;
+        drop(file);
+        std::fs::rename(&temporary, &self.path).map_err(write_error)?;
+
+        Ok(())
+    }
+}
+
+/// The store to use: the key file when PIHOLE_SWITCH_KEYRING_FILE is set, the system keyring otherwise
+pub fn default_store() -> Box<dyn KeyStore> {
+    match key_file(std::env::var_os("PIHOLE_SWITCH_KEYRING_FILE")) {
+        Some(path) => Box::new(FileKeyStore::new(path)),
+        None => Box::new(SystemKeyring),
+    }
+}
+
+// The key file PIHOLE_SWITCH_KEYRING_FILE points to, an empty value counts as unset
+fn key_file(variable: Option<OsString>) -> Option<PathBuf> {
+    variable.filter(|path| !path.is_empty()).map(PathBuf::from)
+}
+
+/// Find the key of a pihole. The stored key wins, the plaintext key from the config
+/// (or PI_HOLE_KEY) is only used when nothing is stored or the keyring can't be reached
+pub fn resolve_key(store: &dyn KeyStore, pihole: &PiHoleConfig) -> Result<String, String> {
+    let stored = store.get(&pihole.name);
+
+    match (stored, &pihole.key) {
+        (Ok(Some(key)), _) => Ok(key),
+        (Ok(None), Some(key)) => Ok(key.clone()),
+        (Err(e), Some(key)) => {
+            log_warn!(format!("{}, using the plaintext key of \"{}\"", e, pihole.name));
+            Ok(key.clone())
+        }
+        (Ok(None), None) => Err(format!(
+            "No api key for pihole \"{}\". Store one with: pihole-switch auth set \"{}\"",
+            pihole.name, pihole.name,
+        )),
+        (Err(e), None) => Err(format!("{} (looking up the key of \"{}\")", e, pihole.name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pihole(name: &str, key: Option<&str>) -> PiHoleConfig {
+        PiHoleConfig {name: name.to_string(), address: "http://pi.hole".to_string(), key: key.map(str::to_string)}
+    }
+
+    fn key_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pihole-switch-keys-{}-{}.toml", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn resolve_key_from_key_file() {
+        let path = key_path("resolve");
+        let store = FileKeyStore::new(path.clone());
+        assert_eq!(store.get("primary"), Ok(None));
+        // Nothing stored, the plaintext key is used
+        assert_eq!(resolve_key(&store, &pihole("primary", Some("plain"))), Ok("plain".to_string()));
+        assert!(resolve_key(&store, &pihole("primary", None)).is_err());
+
+        // The stored key wins over the plaintext one
+        store.set("primary", "stored").unwrap();
+        store.set("secondary", "other").unwrap();
+        assert_eq!(resolve_key(&store, &pihole("primary", Some("plain"))), Ok("stored".to_string()));
+        assert_eq!(resolve_key(&store, &pihole("secondary", None)), Ok("other".to_string()));
+
+        // A broken file falls back to the plaintext key
+        std::fs::write(&path, "not toml [").unwrap();
+        assert_eq!(resolve_key(&store, &pihole("primary", Some("plain"))), Ok("plain".to_string()));
+        assert!(resolve_key(&store, &pihole("primary", None)).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = key_path("mode");
+
+        FileKeyStore::new(path.clone()).set("primary", "stored").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A file others could read is replaced by an owner only one
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        FileKeyStore::new(path.clone()).set("secondary", "other").unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(FileKeyStore::new(path.clone()).get("primary"), Ok(Some("stored".to_string())));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn key_file_variable() {
+        assert_eq!(key_file(None), None);
+        assert_eq!(key_file(Some(OsString::new())), None);
+        assert_eq!(key_file(Some(OsString::from("/tmp/keys.toml"))), Some(PathBuf::from("/tmp/keys.toml")));
+    }
+}
@@ -0,0 +1,248 @@
+/*
+    Session D-Bus interface of the running tray, so shortcuts, shell extensions and scripts
+    can use the app instead of each calling the pihole api themselves.
+
+    Service org.pihole.Switch, object /org/pihole/Switch, interface org.pihole.Switch:
+        Enable() -> s                   new state
+        Disable(t seconds) -> s         0 disables until enabled again
+        Toggle() -> s
+        GetStatus() -> (s state, t seconds until blocking resumes, 0 when no countdown)
+        SetLogLevel(s level)            "debug", "warn", "info, reqwest=debug", ... (see logging.rs)
+        signal StatusChanged(s state, t seconds)
+    States are "enabled", "mixed", "disabled", "unreachable" and "unknown" (before the first status check)
+ */
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use zbus::{fdo, interface};
+use zbus::object_server::SignalEmitter;
+use crate::backend::PiHoleBackend;
+use crate::{log_err, logging};
+use crate::piapi_handler::{BlockingStatus, PiHoleError};
+use crate::pihole_group::{GroupState, PiHoleGroup};
+use crate::poller::Poller;
+use crate::tray_functions;
+use crate::tray_handler::DisableTimer;
+
+/// Well known name taken on the session bus
+pub const NAME: &str = "org.pihole.Switch";
+/// Path of the object
+pub const PATH: &str = "/org/pihole/Switch";
+
+// The exported object
+struct Switch<B> {
+    pi_api: PiHoleGroup<B>,
+    timer: DisableTimer,
+    poller: Poller,
+    // Last state seen by the tray's status check
+    state: Arc<Mutex<GroupState>>,
+}
+
+impl<B: PiHoleBackend + 'static> Switch<B> {
+    // Turn the result of an action into the reply, D-Bus callers get the error message.
+    // Also has the tray check the status straight away so the icon follows
+    fn reply(&self, result: Result<BlockingStatus, PiHoleError>) -> fdo::Result<String> {
+        self.poller.poll_now();
+
+        match result {
+            Ok(status) => Ok(if status.enabled {"enabled"} else {"disabled"}.to_string()),
+            Err(e) => Err(fdo::Error::Failed(e.to_string())),
+        }
+    }
+}
+
+#[interface(name = "org.pihole.Switch")]
+impl<B: PiHoleBackend + 'static> Switch<B> {
+    /// Enable blocking on every pihole
+    async fn enable(&self) -> fdo::Result<String> {
+        let pi_api = self.pi_api.clone();
+        let result = on_runtime(async move {tray_functions::enable(&pi_api).await}).await;
+        if result.is_ok() {
+            self.timer.clear();
+        }
+        self.reply(result)
+    }
+
+    /// Disable blocking for n seconds, 0 for until enabled again
+    async fn disable(&self, seconds: u64) -> fdo::Result<String> {
+        let pi_api = self.pi_api.clone();
+        let result = on_runtime(async move {tray_functions::disable(&pi_api, seconds).await}).await;
+        if result.is_ok() {
+            self.timer.start(seconds);
+        }
+        self.reply(result)
+    }
+
+    /// Disable when enabled, enable otherwise
+    async fn toggle(&self) -> fdo::Result<String> {
+        let pi_api = self.pi_api.clone();
+        let result = on_runtime(async move {tray_functions::toggle(&pi_api).await}).await;
+        if result.is_ok() {
+            // Toggling never leaves a countdown running
+            self.timer.clear();
+        }
+        self.reply(result)
+    }
+
+    /// State shown by the tray and the seconds left on the disable countdown
+    fn get_status(&self) -> (String, u64) {
+        let state = *self.state.lock().unwrap();
+        (state.as_str().to_string(), remaining_secs(&self.timer))
+    }
+
+    /// Change the log level until the tray quits
+    fn set_log_level(&self, level: &str) -> fdo::Result<()> {
+        logging::set_level(level).map_err(fdo::Error::InvalidArgs)
+    }
+
+    /// Sent when the tray sees the state change
+    #[zbus(signal)]
+    async fn status_changed(emitter: &SignalEmitter<'_>, state: &str, seconds: u64) -> zbus::Result<()>;
+}
+
+// zbus runs the methods on its own executor, but the pihole calls need the tokio runtime.
+// Waiting on the spawned task doesn't block zbus, other calls are still answered meanwhile
+async fn on_runtime<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> T {
+    tray_functions::runtime().spawn(future).await.unwrap()
+}
+
+// Countdown in whole seconds, 0 when there is none
+fn remaining_secs(timer: &DisableTimer) -> u64 {
+    timer.remaining().map(|left| left.as_secs()).unwrap_or(0)
+}
+
+/// Handle to the exported object, used by the tray to report state changes
+pub struct Service<B: PiHoleBackend + 'static> {
+    connection: zbus::blocking::Connection,
+    state: Arc<Mutex<GroupState>>,
+    timer: DisableTimer,
+    _backend: std::marker::PhantomData<B>,
+}
+
+/// Take the name on the session bus and export the object
+pub fn start<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>, timer: DisableTimer, poller: Poller, state: GroupState) -> zbus::Result<Service<B>> {
+    serve(zbus::blocking::connection::Builder::session()?, pi_api, timer, poller, state)
+}
+
+// Export the object on the given bus (the session bus, or a private one in the tests)
+fn serve<B: PiHoleBackend + 'static>(builder: zbus::blocking::connection::Builder, pi_api: PiHoleGroup<B>, timer: DisableTimer, poller: Poller, state: GroupState) -> zbus::Result<Service<B>> {
+    let state = Arc::new(Mutex::new(state));
+    let switch = Switch {pi_api, timer: timer.clone(), poller, state: state.clone()};
+
+    let connection = builder
+        .name(NAME)?
+        .serve_at(PATH, switch)?
+        .build()?;
+
+    Ok(Service {connection, state, timer, _backend: std::marker::PhantomData})
+}
+
+impl<B: PiHoleBackend + 'static> Service<B> {
+    /// Remember the state the tray shows, sending StatusChanged when it is different from before
+    pub fn set_state(&self, state: GroupState) {
+        {
+            let mut current = self.state.lock().unwrap();
+            if *current == state {
+                return;
+            }
+            *current = state;
+        }
+
+        let iface = match self.connection.object_server().interface::<_, Switch<B>>(PATH) {
+            Ok(iface) => iface,
+            Err(e) => {
+                log_err!(format!("Error finding D-Bus object: {}", e));
+                return;
+            }
+        };
+
+        let result = zbus::block_on(Switch::<B>::status_changed(
+            iface.signal_emitter(),
+            state.as_str(),
+            remaining_secs(&self.timer),
+        ));
+        if let Err(e) = result {
+            log_err!(format!("Error sending StatusChanged: {}", e));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+    use crate::backend::FakePiHole;
+    use crate::pihole_group::Instance;
+    use crate::poller;
+
+    // A bus of our own so the tests don't touch (or need) the session bus. Killed when dropped.
+    // Also used by the notification tests
+    pub(crate) struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        // None when dbus-daemon isn't installed
+        pub(crate) fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(Self {daemon, address: address.trim().to_string()})
+        }
+
+        pub(crate) fn connect(&self) -> zbus::blocking::connection::Builder<'static> {
+            zbus::blocking::connection::Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn methods_act_on_the_piholes() {
+        let bus = PrivateBus::start().expect("dbus-daemon is needed for the D-Bus tests");
+
+        let fake = FakePiHole::new(true);
+        let group = PiHoleGroup::new(vec![Instance {name: "fake".to_string(), api: fake.clone()}]);
+        let (poller, _polls) = poller::start(group.clone(), Duration::from_secs(60));
+        let timer = DisableTimer::default();
+        let service = serve(bus.connect(), group, timer.clone(), poller, GroupState::Unknown).unwrap();
+
+        let client = bus.connect().build().unwrap();
+        let reply: String = client.call_method(Some(NAME), PATH, Some(NAME), "Disable", &(30u64,)).unwrap().body().deserialize().unwrap();
+        assert_eq!(reply, "disabled");
+        assert_eq!(fake.timer(), Some(30));
+        assert!(timer.remaining().is_some());
+
+        let (state, seconds): (String, u64) = client.call_method(Some(NAME), PATH, Some(NAME), "GetStatus", &()).unwrap().body().deserialize().unwrap();
+        assert_eq!(state, "unknown");
+        assert!(seconds > 0 && seconds <= 30);
+
+        let reply: String = client.call_method(Some(NAME), PATH, Some(NAME), "Toggle", &()).unwrap().body().deserialize().unwrap();
+        assert_eq!(reply, "enabled");
+        assert!(fake.is_enabled());
+        assert!(timer.remaining().is_none());
+
+        // The tray reports what it shows
+        service.set_state(GroupState::Enabled);
+        let (state, _): (String, u64) = client.call_method(Some(NAME), PATH, Some(NAME), "GetStatus", &()).unwrap().body().deserialize().unwrap();
+        assert_eq!(state, "enabled");
+
+        // Failures come back as D-Bus errors with the reason
+        fake.set_unreachable(true);
+        let error = client.call_method(Some(NAME), PATH, Some(NAME), "Enable", &()).unwrap_err();
+        assert!(error.to_string().contains("unreachable"), "{}", error);
+    }
+}
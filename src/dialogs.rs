@@ -0,0 +1,139 @@
+/*
+    Small GTK dialogs used by the linux tray.
+    Must be called on the GTK main thread (tray callbacks run on the tray's own thread,
+    so they hand the work over with glib::idle_add_once)
+ */
+use std::cell::Cell;
+use std::rc::Rc;
+use gtk::prelude::*;
+
+/// Ask for a line of text, starting out as text. Returns None if the dialog was cancelled or closed
+pub fn prompt(title: &str, message: &str, placeholder: &str, text: &str) -> Option<String> {
+    let dialog = gtk::Dialog::with_buttons(
+        Some(title),
+        None::<&gtk::Window>,
+        gtk::DialogFlags::MODAL,
+        &[("Cancel", gtk::ResponseType::Cancel), ("OK", gtk::ResponseType::Accept)],
+    );
+    // There is no parent window, make sure it doesn't open behind everything else
+    dialog.set_keep_above(true);
+    dialog.set_position(gtk::WindowPosition::Center);
+
+    // Pressing enter in the entry is the same as clicking OK
+    let entry = gtk::Entry::new();
+    entry.set_placeholder_text(Some(placeholder));
+    entry.set_text(text);
+    entry.set_activates_default(true);
+    dialog.set_default_response(gtk::ResponseType::Accept);
+
+    let content = dialog.content_area();
+    content.set_spacing(6);
+    content.set_border_width(12);
+    content.add(&gtk::Label::new(Some(message)));
+    content.add(&entry);
+
+    dialog.show_all();
+    let response = dialog.run();
+    let text = entry.text().to_string();
+    unsafe { dialog.destroy(); }
+
+    if response == gtk::ResponseType::Accept {
+        Some(text)
+    } else {
+        None
+    }
+}
+
+/// Text on the clipboard, if it holds any
+pub fn clipboard_text() -> Option<String> {
+    gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).wait_for_text().map(|text| text.to_string())
+}
+
+/// Show an error message and wait for it to be closed
+pub fn show_error(title: &str, message: &str) {
+    show_message(title, message, gtk::MessageType::Error);
+}
+
+/// Show a message (ex. that an action worked) and wait for it to be closed
+pub fn show_info(title: &str, message: &str) {
+    show_message(title, message, gtk::MessageType::Info);
+}
+
+fn show_message(title: &str, message: &str, kind: gtk::MessageType) {
+    let dialog = gtk::MessageDialog::new(
+        None::<&gtk::Window>,
+        gtk::DialogFlags::MODAL,
+        kind,
+        gtk::ButtonsType::Ok,
+        message,
+    );
+    dialog.set_title(title);
+    dialog.set_keep_above(true);
+
+    dialog.run();
+    unsafe { dialog.destroy(); }
+}
+
+/// A window showing the output of a long running action (ex. a gravity update) as it comes in,
+/// with a status line below it
+pub struct OutputWindow {
+    view: gtk::TextView,
+    // Stays at the end of the text, for scrolling down to the newest line
+    end: gtk::TextMark,
+    status: gtk::Label,
+    closed: Rc<Cell<bool>>,
+}
+
+impl OutputWindow {
+    /// Open the window, empty apart from the status line
+    pub fn open(title: &str, status: &str) -> Self {
+        let window = gtk::Window::new(gtk::WindowType::Toplevel);
+        window.set_title(title);
+        window.set_default_size(700, 400);
+        window.set_position(gtk::WindowPosition::Center);
+
+        let view = gtk::TextView::new();
+        view.set_editable(false);
+        view.set_cursor_visible(false);
+        view.set_monospace(true);
+        let buffer = view.buffer().unwrap();
+        // Right gravity, so text added at the end goes before it
+        let end = buffer.create_mark(None, &buffer.end_iter(), false).expect("end mark");
+
+        let scrolled = gtk::ScrolledWindow::new(None::<&gtk::Adjustment>, None::<&gtk::Adjustment>);
+        scrolled.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+        scrolled.add(&view);
+
+        let status = gtk::Label::new(Some(status));
+        status.set_xalign(0.0);
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        content.set_border_width(8);
+        content.pack_start(&scrolled, true, true, 0);
+        content.pack_start(&status, false, false, 0);
+        window.add(&content);
+
+        let closed = Rc::new(Cell::new(false));
+        let closed_clone = closed.clone();
+        window.connect_destroy(move |_| closed_clone.set(true));
+
+        window.show_all();
+        Self {view, end, status, closed}
+    }
+
+    /// Add a line at the end and scroll down to it
+    pub fn add_line(&self, line: &str) {
+        let buffer = self.view.buffer().unwrap();
+        buffer.insert(&mut buffer.end_iter(), &format!("{}\n", line));
+        self.view.scroll_to_mark(&self.end, 0.0, false, 0.0, 0.0);
+    }
+
+    pub fn set_status(&self, text: &str) {
+        self.status.set_text(text);
+    }
+
+    /// True once the window was closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+}
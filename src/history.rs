@@ -0,0 +1,237 @@
+/*
+    Audit history of the actions sent to the piholes (enable, disable, toggle), from the tray, the CLI and D-Bus alike.
+    Kept in history.jsonl next to the log ($XDG_STATE_HOME/pihole-switch), one JSON object per line.
+    Lines are only ever appended, nothing in the app rewrites or trims the file
+ */
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use crate::config;
+use crate::log_warn;
+use crate::piapi_handler::{BlockingStatus, PiHoleError};
+use crate::tray_functions::describe_duration;
+
+/// One action
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Local time the action finished, RFC 3339
+    pub timestamp: String,
+    /// Account that ran it ($USER, %USERNAME% on windows)
+    pub user: String,
+    /// "enable", "disable" or "toggle"
+    pub action: String,
+    /// Seconds for a timed disable, None for until enabled again
+    pub duration: Option<u64>,
+    /// Pihole address, comma separated when the action went to several
+    pub host: String,
+    /// The new state ("enabled"/"disabled") or why it failed
+    pub result: String,
+}
+
+/// Column names of the CSV export, in field order
+pub const CSV_HEADER: &str = "timestamp,user,action,duration,host,result";
+
+// Counts the entries recorded by this process, so the tray knows when to refresh its menu
+static RECORDED: AtomicU64 = AtomicU64::new(0);
+
+/// Where the history is kept
+pub fn path() -> Option<PathBuf> {
+    config::state_dir().map(|dir| dir.join("history.jsonl"))
+}
+
+/// Append an action and its outcome. Failing to write is logged, never fails the action itself
+pub fn record(action: &str, duration: Option<u64>, host: String, result: &Result<BlockingStatus, PiHoleError>) {
+    let entry = Entry {
+        timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+        user: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "unknown".to_string()),
+        action: action.to_string(),
+        duration,
+        host,
+        result: match result {
+            Ok(status) => if status.enabled {"enabled"} else {"disabled"}.to_string(),
+            Err(e) => format!("failed: {}", e),
+        },
+    };
+
+    if let Err(e) = append(&entry) {
+        log_warn!(format!("Could not write the action history: {}", e));
+    }
+    RECORDED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Write one line at the end of the file
+fn append(entry: &Entry) -> Result<(), String> {
+    let path = path().ok_or("No folder for the history".to_string())?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    // One write per line so entries from the tray and the CLI don't interleave
+    let line = format!("{}\n", serde_json::to_string(entry).unwrap());
+    file.write_all(line.as_bytes()).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Number of entries recorded by this process so far
+pub fn recorded() -> u64 {
+    RECORDED.load(Ordering::Relaxed)
+}
+
+/// Every entry, oldest first. No file yet means no history
+pub fn read() -> Result<Vec<Entry>, String> {
+    read_from(&path().ok_or("No folder for the history".to_string())?)
+}
+
+// Every entry in the given file
+fn read_from(path: &Path) -> Result<Vec<Entry>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        // Skip a damaged line (ex. the disk filled up mid write) instead of losing the whole history
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log_warn!(format!("Skipping line {} of {}: {}", number + 1, path.display(), e)),
+        }
+    }
+    Ok(entries)
+}
+
+/// The last n entries, newest first
+pub fn recent(n: usize) -> Vec<Entry> {
+    match read() {
+        Ok(entries) => entries.into_iter().rev().take(n).collect(),
+        Err(e) => {
+            log_warn!(e);
+            Vec::new()
+        }
+    }
+}
+
+/// Entries as CSV, with a header line
+pub fn to_csv(entries: &[Entry]) -> String {
+    let mut lines = vec![CSV_HEADER.to_string()];
+    for entry in entries {
+        let fields = [
+            entry.timestamp.clone(),
+            entry.user.clone(),
+            entry.action.clone(),
+            entry.duration.map(|seconds| seconds.to_string()).unwrap_or_default(),
+            entry.host.clone(),
+            entry.result.clone(),
+        ];
+        lines.push(fields.iter().map(|field| csv_field(field)).collect::<Vec<_>>().join(","));
+    }
+    lines.join("\n")
+}
+
+// Quote a field when it has a comma, quote or line break in it (hosts of a group, error messages)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl Entry {
+    /// One line summary like "10:42 Disable 5 minutes: disabled".
+    /// The time gets the date in front when it isn't from today
+    pub fn describe(&self) -> String {
+        let time = match DateTime::parse_from_rfc3339(&self.timestamp) {
+            Ok(time) => {
+                let time = time.with_timezone(&Local);
+                if time.date_naive() == Local::now().date_naive() {
+                    time.format("%H:%M").to_string()
+                } else {
+                    time.format("%b %d %H:%M").to_string()
+                }
+            }
+            Err(_) => self.timestamp.clone(),
+        };
+
+        format!("{} {}: {}", time, self.describe_action(), self.result)
+    }
+
+    /// The action with its duration, like "Disable 5 minutes"
+    pub fn describe_action(&self) -> String {
+        match (self.action.as_str(), self.duration) {
+            ("enable", _) => "Enable".to_string(),
+            ("disable", Some(seconds)) => format!("Disable {}", describe_duration(seconds)),
+            ("disable", None) => "Disable".to_string(),
+            ("toggle", _) => "Toggle".to_string(),
+            (other, _) => other.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(host: &str, result: &str) -> Entry {
+        Entry {
+            timestamp: "2026-10-18T10:42:00+02:00".to_string(),
+            user: "pi".to_string(),
+            action: "disable".to_string(),
+            duration: Some(300),
+            host: host.to_string(),
+            result: result.to_string(),
+        }
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("pi.hole"), "pi.hole");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("10.0.0.2,10.0.0.3"), "\"10.0.0.2,10.0.0.3\"");
+        assert_eq!(csv_field("failed: \"busy\""), "\"failed: \"\"busy\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("line\rbreak"), "\"line\rbreak\"");
+    }
+
+    #[test]
+    fn csv_export() {
+        assert_eq!(to_csv(&[]), CSV_HEADER);
+
+        let mut enable = entry("pi.hole", "enabled");
+        enable.action = "enable".to_string();
+        enable.duration = None;
+        let csv = to_csv(&[entry("10.0.0.2,10.0.0.3", "failed: \"busy\""), enable]);
+        assert_eq!(csv, [
+            CSV_HEADER,
+            "2026-10-18T10:42:00+02:00,pi,disable,300,\"10.0.0.2,10.0.0.3\",\"failed: \"\"busy\"\"\"",
+            "2026-10-18T10:42:00+02:00,pi,enable,,pi.hole,enabled",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn damaged_lines_are_skipped() {
+        // A file of its own, the shared test history gets written to by the other tests
+        let path = std::env::temp_dir().join(format!("pihole-switch-history-{}.jsonl", std::process::id()));
+        assert_eq!(read_from(&path), Ok(Vec::new()));
+
+        let first = entry("pi.hole", "disabled");
+        let second = entry("10.0.0.2", "failed: Pihole unreachable");
+        std::fs::write(&path, format!(
+            "{}\n{{\"timestamp\": \"2026-10\n\n{}\nnot json\n",
+            serde_json::to_string(&first).unwrap(),
+            serde_json::to_string(&second).unwrap(),
+        )).unwrap();
+        assert_eq!(read_from(&path), Ok(vec![first, second]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
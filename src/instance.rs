@@ -0,0 +1,225 @@
+/*
+    Make sure only one tray runs at a time.
+    The running tray listens on a unix socket in $XDG_RUNTIME_DIR. A second launch connects to it:
+    without a command it just exits, with one (toggle, disable 5m, ...) the command is run by the tray
+    and the reply printed, so the CLI drives the live tray instead of racing it
+ */
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::backend::PiHoleBackend;
+use crate::cli::{self, Action, Reply};
+use crate::log_err;
+use crate::pihole_group::PiHoleGroup;
+use crate::poller::Poller;
+use crate::tray_handler::DisableTimer;
+
+// How long the tray waits on a client that connected but doesn't send or read
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A command sent by a second launch, one JSON line per connection
+#[derive(Serialize, Deserialize)]
+struct Request {
+    command: Vec<String>,
+    json: bool,
+}
+
+/// Where the socket of the running tray lives
+pub fn socket_path() -> PathBuf {
+    // $XDG_RUNTIME_DIR is private to the user. Without it fall back to /tmp with the user in the name
+    match dirs::runtime_dir() {
+        Some(dir) => dir.join("pihole-switch.sock"),
+        None => std::env::temp_dir().join(format!("pihole-switch-{}.sock", std::env::var("USER").unwrap_or_default())),
+    }
+}
+
+/// Try to become the running instance. Returns None when another tray already holds the socket
+pub fn acquire() -> std::io::Result<Option<UnixListener>> {
+    let path = socket_path();
+
+    // Someone answering means a tray is running
+    if UnixStream::connect(&path).is_ok() {
+        return Ok(None);
+    }
+
+    // Nobody answered, so a socket file left over is from a tray that crashed
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+
+    match UnixListener::bind(&path) {
+        Ok(listener) => Ok(Some(listener)),
+        // Lost a race against another launch
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove the socket when the tray quits
+pub fn release() {
+    if let Err(e) = std::fs::remove_file(socket_path()) {
+        log_err!(format!("Error removing socket: {}", e));
+    }
+}
+
+/// Send a command to the running tray. Returns None when no tray is running
+pub fn forward(command: &[String], json: bool) -> Option<Reply> {
+    send(&socket_path(), command, json)
+}
+
+// Send a command to whoever listens on the socket at path
+fn send(path: &Path, command: &[String], json: bool) -> Option<Reply> {
+    let stream = UnixStream::connect(path).ok()?;
+
+    // The tray might be stuck on a dialog or a slow pihole, don't hang forever
+    let timeout = Some(Duration::from_secs(30));
+    stream.set_read_timeout(timeout).ok()?;
+    stream.set_write_timeout(timeout).ok()?;
+
+    let request = serde_json::to_string(&Request {command: command.to_vec(), json}).unwrap();
+    let result = (&stream).write_all(format!("{}\n", request).as_bytes())
+        .and_then(|_| {
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).map(|_| line)
+        });
+
+    match result.map(|line| serde_json::from_str::<Reply>(&line)) {
+        Ok(Ok(reply)) => Some(reply),
+        Ok(Err(e)) => Some(Reply {code: cli::EXIT_OTHER, stdout: String::new(), stderr: format!("Bad reply from the running tray: {}", e)}),
+        Err(e) => Some(Reply {code: cli::EXIT_OTHER, stdout: String::new(), stderr: format!("Could not talk to the running tray: {}", e)}),
+    }
+}
+
+/// Answer commands from other launches on a background thread
+pub fn serve<B: PiHoleBackend + 'static>(listener: UnixListener, pi_api: PiHoleGroup<B>, timer: DisableTimer, poller: Poller) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => handle(stream, &pi_api, &timer, &poller),
+                Err(e) => log_err!(format!("Error accepting connection: {}", e)),
+            }
+        }
+    });
+}
+
+// Run one forwarded command and send the reply back
+fn handle<B: PiHoleBackend>(stream: UnixStream, pi_api: &PiHoleGroup<B>, timer: &DisableTimer, poller: &Poller) {
+    // Connections are answered one by one, a client that hangs must not hold up everyone after it
+    if let Err(e) = stream.set_read_timeout(Some(CLIENT_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT))) {
+        log_err!(format!("Error setting socket timeout: {}", e));
+        return;
+    }
+
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(&stream).read_line(&mut line) {
+        log_err!(format!("Error reading command: {}", e));
+        return;
+    }
+
+    // acquire() of a second tray only checks someone is listening and hangs up
+    if line.is_empty() {
+        return;
+    }
+
+    let reply = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => match cli::parse_action(&request.command) {
+            Ok(Some(action)) => {
+                let reply = cli::execute(pi_api, action, request.json);
+                // Keep the countdown in the menu in line with what was just done
+                if reply.code == cli::EXIT_OK {
+                    match action {
+                        Action::Disable(seconds) => timer.start(seconds),
+                        Action::Enable | Action::Toggle => timer.clear(),
+                        Action::Status => {}
+                    }
+                }
+                // Show the result on the icon straight away
+                if action != Action::Status {
+                    poller.poll_now();
+                }
+                reply
+            }
+            Ok(None) => Reply {code: cli::EXIT_USAGE, stdout: String::new(), stderr: format!("The running tray can't run: {}", request.command.join(" "))},
+            Err(e) => Reply {code: cli::EXIT_USAGE, stdout: String::new(), stderr: e},
+        },
+        Err(e) => Reply {code: cli::EXIT_USAGE, stdout: String::new(), stderr: format!("Bad request: {}", e)},
+    };
+
+    let text = serde_json::to_string(&reply).unwrap();
+    if let Err(e) = (&stream).write_all(format!("{}\n", text).as_bytes()) {
+        log_err!(format!("Error sending reply: {}", e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakePiHole;
+    use crate::pihole_group::Instance;
+    use crate::poller;
+
+    // Serve a fake pihole on a socket in a temp dir
+    fn serving(name: &str) -> (PathBuf, FakePiHole, DisableTimer) {
+        let dir = std::env::temp_dir().join(format!("pihole-switch-socket-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pihole-switch.sock");
+
+        let fake = FakePiHole::new(true);
+        let group = || PiHoleGroup::new(vec![Instance {name: "primary".to_string(), api: fake.clone()}]);
+        let (poller, _polls) = poller::start(group(), Duration::from_secs(3600));
+        let timer = DisableTimer::default();
+        serve(UnixListener::bind(&path).unwrap(), group(), timer.clone(), poller);
+
+        (path, fake, timer)
+    }
+
+    fn words(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (path, fake, timer) = serving("round-trip");
+
+        let reply = send(&path, &words("disable 5m"), false).unwrap();
+        assert_eq!(reply, Reply {code: cli::EXIT_OK, stdout: "Blocking disabled for 5 minutes".to_string(), stderr: String::new()});
+        assert!(!fake.is_enabled());
+        assert!(timer.remaining().is_some());
+
+        let reply = send(&path, &words("status"), false).unwrap();
+        assert_eq!(reply.code, cli::EXIT_DISABLED);
+
+        let reply = send(&path, &words("gravity"), false).unwrap();
+        assert_eq!(reply.code, cli::EXIT_USAGE);
+        assert_eq!(reply.stderr, "The running tray can't run: gravity");
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn bad_requests() {
+        let (path, fake, _timer) = serving("bad-requests");
+
+        // Not JSON
+        let stream = UnixStream::connect(&path).unwrap();
+        (&stream).write_all(b"toggle\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        let reply: Reply = serde_json::from_str(&line).unwrap();
+        assert_eq!(reply.code, cli::EXIT_USAGE);
+        assert!(reply.stderr.starts_with("Bad request: "), "{}", reply.stderr);
+
+        // A probe like acquire()'s gets no reply and the next request is still answered
+        drop(UnixStream::connect(&path).unwrap());
+        let reply = send(&path, &words("toggle"), true).unwrap();
+        assert_eq!(reply.code, cli::EXIT_OK);
+        assert!(!fake.is_enabled());
+        // Only the toggle acted, the malformed line was never run
+        assert_eq!(fake.calls().iter().filter(|call| !["status", "summary"].contains(&call.as_str())).collect::<Vec<_>>(), ["disable 0"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -7,14 +7,32 @@
 use crate::*;
 use gtk;
 use gtk_sys;
+use crate::backend::PiHoleBackend;
+use crate::piapi_handler::{DomainList, PiHoleError};
+use crate::pihole_group::PiHoleGroup;
+use crate::poller::{Poll, Poller};
 
-pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::TrayIcon) {
+pub fn main<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>, mut pi_tray:tray_handler::TrayIcon, presets: Vec<config::Preset>, poller: Poller, status_rx: std::sync::mpsc::Receiver<Poll>, notify: config::Notifications) {
+
+    // Desktop notifications on state changes and finished gravity updates. Without a session bus the tray still works
+    let notifier = match notifications::start(notify, poller.clone()) {
+        Ok(notifier) => Some(notifier),
+        Err(e) => {
+            log_warn!(format!("Could not start notifications: {}", e));
+            None
+        }
+    };
+
+    // Add the status line (shows the disable countdown), updated by update_status_icon
+    let id = pi_tray.tray.inner_mut().add_menu_item_with_id("Checking status...", || {}).unwrap();
+    pi_tray.set_status_label(id);
 
     // Add "Open in Browser" Button
     // Open the pihole dashboard in the default browser
     let pi_api_clone = pi_api.clone();
     pi_tray.tray.add_menu_item("Open in Browser", move || {
-        block_on!(async{tray_functions::open_browser(&pi_api_clone).await});
+        let pi_api_clone = pi_api_clone.clone();
+        tray_functions::spawn(async move {tray_functions::open_browser(&pi_api_clone).await});
     })
     .unwrap();
 
@@ -24,38 +42,184 @@ pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::Tray
     // Add the "toggle" Button
     // Toggle the state of pihole
     let pi_api_clone = pi_api.clone();
+    let timer = pi_tray.timer();
+    let poller_clone = poller.clone();
     pi_tray.tray.add_menu_item("Toggle", move || {
-        block_on!(async{tray_functions::toggle_pihole(&pi_api_clone).await});
+        // Toggling never leaves a countdown running
+        timer.clear();
+        let pi_api_clone = pi_api_clone.clone();
+        let poller_clone = poller_clone.clone();
+        tray_functions::spawn(async move {
+            tray_functions::toggle_pihole(&pi_api_clone).await;
+            poller_clone.poll_now();
+        });
+    })
+    .unwrap();
+
+    // Setup a disable button for each preset
+    for preset in presets {
+        let pi_api_clone = pi_api.clone();
+        let timer = pi_tray.timer();
+        let poller_clone = poller.clone();
+        pi_tray.tray.add_menu_item(&preset.label, move || {
+            let pi_api_clone = pi_api_clone.clone();
+            let timer = timer.clone();
+            let poller_clone = poller_clone.clone();
+            let seconds = preset.seconds;
+            tray_functions::spawn(async move {
+                if tray_functions::disable_sec(&pi_api_clone, seconds).await {
+                    timer.start(seconds);
+                }
+                poller_clone.poll_now();
+            });
+        })
+        .unwrap();
+    }
+
+    // Setup custom disable button
+    // Ask how long to disable pihole for (ex. 90s, 15m, 1h30m)
+    let pi_api_clone = pi_api.clone();
+    let timer = pi_tray.timer();
+    let poller_clone = poller.clone();
+    pi_tray.tray.add_menu_item("Disable for...", move || {
+        let pi_api_clone = pi_api_clone.clone();
+        let timer = timer.clone();
+        let poller_clone = poller_clone.clone();
+
+        // Dialogs have to be opened on the GTK thread, not the tray's
+        glib::idle_add_once(move || {
+            let Some(text) = dialogs::prompt("Disable Pi-hole", "Disable blocking for:", "ex. 90s, 15m, 1h30m", "") else {
+                return; // Cancelled
+            };
+
+            match tray_functions::parse_duration(&text) {
+                Ok(seconds) => {
+                    // Don't hold up the GTK thread while the pihole answers
+                    tray_functions::spawn(async move {
+                        if tray_functions::disable_sec(&pi_api_clone, seconds).await {
+                            timer.start(seconds);
+                        }
+                        poller_clone.poll_now();
+                    });
+                }
+                Err(e) => dialogs::show_error("Disable Pi-hole", &e),
+            }
+        });
+    })
+    .unwrap();
+
+    // Add break line
+    pi_tray.tray.inner_mut().add_separator().unwrap();
+
+    // Setup the allow/block domain buttons
+    // Ask for a domain (the one on the clipboard to start with) and put it on the list
+    for (label, title, list) in [("Allow domain...", "Allow domain", DomainList::Allow), ("Block domain...", "Block domain", DomainList::Deny)] {
+        let pi_api_clone = pi_api.clone();
+        pi_tray.tray.add_menu_item(label, move || {
+            let pi_api_clone = pi_api_clone.clone();
+            // Dialogs have to be opened on the GTK thread, not the tray's
+            glib::idle_add_once(move || add_domain_dialog(pi_api_clone, title, list));
+        })
+        .unwrap();
+    }
+
+    // Setup the temporary allow button
+    // Ask for a domain and how long to allow it, it comes off the allowlist again by itself (see temporary)
+    let pi_api_clone = pi_api.clone();
+    pi_tray.tray.add_menu_item("Allow domain for...", move || {
+        let pi_api_clone = pi_api_clone.clone();
+        glib::idle_add_once(move || allow_for_dialog(pi_api_clone));
     })
     .unwrap();
 
-    // Setup disable button
-    // Disable pihole 10 seconds
+    // Setup the check button
+    // Ask for a domain and show which list entries match it
     let pi_api_clone = pi_api.clone();
-    pi_tray.tray.add_menu_item("Disable 10 Seconds", move || {
-        block_on!(async{tray_functions::disable_sec(&pi_api_clone, 10).await});
+    pi_tray.tray.add_menu_item("Why is this blocked?", move || {
+        let pi_api_clone = pi_api_clone.clone();
+        glib::idle_add_once(move || check_dialog(pi_api_clone));
     })
     .unwrap();
 
-    // Setup disable button
-    // Disable pihole 30 seconds
+    // Setup the query log button
+    // Opens a window following the query log, with allow/block buttons for the selected query
     let pi_api_clone = pi_api.clone();
-    pi_tray.tray.add_menu_item("Disable 30 Seconds", move || {
-        block_on!(async{tray_functions::disable_sec(&pi_api_clone, 30).await});
+    pi_tray.tray.add_menu_item("Query log...", move || {
+        let pi_api_clone = pi_api_clone.clone();
+        glib::idle_add_once(move || querylog_window::open(pi_api_clone));
     })
     .unwrap();
 
-    // Setup disable button
-    // Disable pihole 5 minutes
+    // Setup the gravity button
+    // Download the blocklists again, with the output in a window and a notification when done
     let pi_api_clone = pi_api.clone();
-    pi_tray.tray.add_menu_item("Disable 5 minutes", move || {
-        block_on!(async{tray_functions::disable_sec(&pi_api_clone, 60*5).await});
+    let notifier_clone = notifier.clone();
+    pi_tray.tray.add_menu_item("Update Gravity", move || {
+        let pi_api_clone = pi_api_clone.clone();
+        let notifier_clone = notifier_clone.clone();
+        glib::idle_add_once(move || gravity_dialog(pi_api_clone, notifier_clone));
     })
     .unwrap();
 
     // Add break line
     pi_tray.tray.inner_mut().add_separator().unwrap();
 
+    // Add a section for each pihole when there is more than one
+    if pi_api.instances().len() > 1 {
+        for (index, instance) in pi_api.instances().iter().enumerate() {
+            // Status label, updated by update_status_icon
+            let id = pi_tray.tray.inner_mut().add_menu_item_with_id(&instance.name, || {}).unwrap();
+            pi_tray.add_instance_label(id);
+
+            // Toggle only this pihole
+            let pi_api_clone = pi_api.clone();
+            let poller_clone = poller.clone();
+            pi_tray.tray.add_menu_item(&format!("    Toggle {}", instance.name), move || {
+                let pi_api_clone = pi_api_clone.clone();
+                let poller_clone = poller_clone.clone();
+                tray_functions::spawn(async move {
+                    tray_functions::toggle_pihole(&pi_api_clone.instances()[index].api).await;
+                    poller_clone.poll_now();
+                });
+            })
+            .unwrap();
+
+            // Open only this pihole's dashboard
+            let pi_api_clone = pi_api.clone();
+            pi_tray.tray.add_menu_item(&format!("    Open {}", instance.name), move || {
+                let pi_api_clone = pi_api_clone.clone();
+                tray_functions::spawn(async move {tray_functions::open_browser(&pi_api_clone.instances()[index].api).await});
+            })
+            .unwrap();
+        }
+
+        // Add break line
+        pi_tray.tray.inner_mut().add_separator().unwrap();
+    }
+
+    // Today's numbers from the dashboard, refreshed on every poll.
+    // tray-item can't do submenus, so this and the recent actions get their own sections
+    pi_tray.tray.inner_mut().add_menu_item_with_id("Stats", || {}).unwrap();
+    for _ in 0..tray_handler::STATS_LINES {
+        let id = pi_tray.tray.inner_mut().add_menu_item_with_id("", || {}).unwrap();
+        pi_tray.add_stats_label(id);
+    }
+    pi_tray.update_stats(None);
+
+    // Add break line
+    pi_tray.tray.inner_mut().add_separator().unwrap();
+
+    // Recent actions, newest first (see history)
+    pi_tray.tray.inner_mut().add_menu_item_with_id("Recent actions", || {}).unwrap();
+    for _ in 0..tray_handler::HISTORY_LINES {
+        let id = pi_tray.tray.inner_mut().add_menu_item_with_id("", || {}).unwrap();
+        pi_tray.add_history_label(id);
+    }
+    pi_tray.update_history();
+
+    // Add break line
+    pi_tray.tray.inner_mut().add_separator().unwrap();
+
     // Add quit button (exits the app)
     pi_tray.tray.add_menu_item("Quit", move || {
         unsafe { gtk_sys::gtk_main_quit(); } // TODO: Recommended method from the docs but should ideally try to find a better method
@@ -64,10 +228,42 @@ pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::Tray
     .unwrap();
 
 
-    // Setup status checks for icon with a delay
-    glib::timeout_add_local(std::time::Duration::from_secs(1), move || {
-        let pi_api_clone = pi_api.clone();
-        pi_tray.update_status_icon(pi_api_clone);
+    // Export the D-Bus interface (org.pihole.Switch). The tray works fine without it
+    let service = match dbus::start(pi_api.clone(), pi_tray.timer(), poller.clone(), pi_tray.state()) {
+        Ok(service) => Some(service),
+        Err(e) => {
+            log_warn!(format!("Could not start the D-Bus service: {}", e));
+            None
+        }
+    };
+
+    if let Some(notifier) = notifier {
+        pi_tray.set_notifier(notifier);
+    }
+
+    // Status checks run in the background (see poller), the GTK thread only picks up the results
+    glib::timeout_add_local(std::time::Duration::from_millis(250), move || {
+        // Show whatever came in since the last tick
+        while let Ok(poll) = status_rx.try_recv() {
+            // An action finished while this poll was out, so it may show the state from before
+            // (ex. still enabled right after a disable, which would clear the new countdown).
+            // Skip it, poll_now already asked for a fresh one
+            if poller.poked_since(poll.started) {
+                continue;
+            }
+            pi_tray.update_status_icon(&pi_api, &poll);
+            // Back off while the piholes are unreachable
+            poller.set_interval(pi_tray.poll_interval(poller.base_interval()));
+        }
+        // Keep the countdown moving between polls
+        pi_tray.update_status_text();
+        // Show actions from the menu, CLI and D-Bus
+        pi_tray.update_history();
+
+        // Let D-Bus listeners know when the state changed
+        if let Some(service) = &service {
+            service.set_state(pi_tray.state());
+        }
         glib::ControlFlow::Continue
     });
 
@@ -77,3 +273,132 @@ pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::Tray
     log_info!("Setup Complete! Entering Mainloop.");
     gtk::main();
 }
+
+// Ask for a domain, starting with the one on the clipboard. None when cancelled or not a domain (already shown)
+fn ask_domain(title: &str, message: &str) -> Option<String> {
+    // Someone reporting a broken site usually sends the link, so start with whatever is on the clipboard
+    let copied = dialogs::clipboard_text()
+        .and_then(|text| tray_functions::domain_from_text(&text))
+        .unwrap_or_default();
+
+    let text = dialogs::prompt(title, message, "ex. example.com", &copied)?;
+    let domain = tray_functions::domain_from_text(&text);
+    if domain.is_none() {
+        dialogs::show_error(title, &format!("\"{}\" is not a domain", text.trim()));
+    }
+    domain
+}
+
+// Ask for a domain and add it to the list, then show how it went. Runs on the GTK thread
+fn add_domain_dialog<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>, title: &'static str, list: DomainList) {
+    let Some(domain) = ask_domain(title, &format!("Domain to add to the {}:", list.describe())) else {
+        return;
+    };
+
+    // Don't hold up the GTK thread while the pihole answers
+    tray_functions::spawn(async move {
+        let result = tray_functions::add_domain(&pi_api, list, &domain).await;
+
+        // Back to the GTK thread for the result
+        glib::idle_add_once(move || match result {
+            Ok(()) => dialogs::show_info(title, &format!("Added {} to the {}", domain, list.describe())),
+            Err(e) => dialogs::show_error(title, &e.to_string()),
+        });
+    });
+}
+
+// Ask for a domain and how long to allow it, then show how it went. Runs on the GTK thread
+fn allow_for_dialog<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>) {
+    let title = "Allow domain for a while";
+    let Some(domain) = ask_domain(title, "Domain to allow:") else {
+        return;
+    };
+    let Some(text) = dialogs::prompt(title, &format!("Allow {} for:", domain), "ex. 5m, 1h", "5m") else {
+        return; // Cancelled
+    };
+    let seconds = match tray_functions::parse_duration(&text) {
+        Ok(seconds) => seconds,
+        Err(e) => {
+            dialogs::show_error(title, &e);
+            return;
+        }
+    };
+
+    // Don't hold up the GTK thread while the pihole answers
+    tray_functions::spawn(async move {
+        let result = tray_functions::allow_domain_for(&pi_api, &domain, seconds).await;
+
+        // Back to the GTK thread for the result
+        glib::idle_add_once(move || match result {
+            Ok(()) => dialogs::show_info(title, &format!("Allowed {} for {}", domain, tray_functions::describe_duration(seconds))),
+            Err(e) => dialogs::show_error(title, &e.to_string()),
+        });
+    });
+}
+
+// Ask for a domain and show why it is (not) blocked. Runs on the GTK thread
+fn check_dialog<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>) {
+    let title = "Why is this blocked?";
+    let Some(domain) = ask_domain(title, "Domain to check:") else {
+        return;
+    };
+
+    // Don't hold up the GTK thread while the pihole answers
+    tray_functions::spawn(async move {
+        let result = tray_functions::check_domain(&pi_api, &domain).await;
+
+        // Back to the GTK thread for the result
+        glib::idle_add_once(move || match result {
+            Ok(result) => dialogs::show_info(title, &tray_functions::describe_search(&result)),
+            Err(e) => dialogs::show_error(title, &e.to_string()),
+        });
+    });
+}
+
+// What a running gravity update sends its window
+enum GravityOutput {
+    Line(String),
+    Done(Result<(), PiHoleError>),
+}
+
+// Update gravity with the output shown in a window as it comes in. Runs on the GTK thread
+fn gravity_dialog<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>, notifier: Option<notifications::Notifier>) {
+    let output = dialogs::OutputWindow::open("Update Gravity", "Updating gravity...");
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    // Don't hold up the GTK thread while the piholes work. Closing the window doesn't stop the update
+    tray_functions::spawn(async move {
+        let line_sender = sender.clone();
+        let progress = move |line: &str| {
+            let _ = line_sender.send(GravityOutput::Line(line.to_string()));
+        };
+        let result = tray_functions::update_gravity(&pi_api, &progress).await;
+
+        if let Some(notifier) = &notifier {
+            notifier.gravity_done(&result);
+        }
+        let _ = sender.send(GravityOutput::Done(result));
+    });
+
+    // Back on the GTK thread, show the output until it is done or the window is closed
+    glib::timeout_add_local(std::time::Duration::from_millis(100), move || {
+        if output.is_closed() {
+            return glib::ControlFlow::Break;
+        }
+
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                GravityOutput::Line(line) => output.add_line(&line),
+                GravityOutput::Done(Ok(())) => {
+                    output.set_status("Gravity updated");
+                    return glib::ControlFlow::Break;
+                }
+                GravityOutput::Done(Err(e)) => {
+                    output.set_status(&format!("Gravity update failed: {}", e));
+                    return glib::ControlFlow::Break;
+                }
+            }
+        }
+        glib::ControlFlow::Continue
+    });
+}
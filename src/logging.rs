@@ -0,0 +1,176 @@
+/*
+    Logging. Everything goes through the log crate (log_info!, log_warn!, log_err! or log::debug! ...) and ends up in
+    pihole-switch_rCURRENT.log in $XDG_STATE_HOME/pihole-switch (~/.local/state/pihole-switch, %LOCALAPPDATA%\pihole-switch on windows).
+    The tray rotates it (pihole-switch_r00000.log, _r00001, ...) and also prints warnings and errors to stderr.
+    The level is "info" unless PIHOLE_SWITCH_LOG or log_level in the config say otherwise, and can be changed while running (D-Bus SetLogLevel).
+    Levels use the env_logger syntax: "debug", "warn", "info, reqwest=debug", ...
+ */
+use std::io::Write;
+use std::sync::OnceLock;
+use flexi_logger::{Cleanup, Criterion, DeferredNow, Duplicate, FileSpec, LogSpecification, Logger, LoggerHandle, Naming};
+use log::{Level, Record};
+use crate::{config, log_warn};
+
+/// Level used when nothing else is configured
+pub const DEFAULT_LEVEL: &str = "info";
+/// Environment variable overriding the level from the config
+pub const LEVEL_VAR: &str = "PIHOLE_SWITCH_LOG";
+
+// Rotate once the file reaches 1 MB, keeping the last 4 old files next to it
+const ROTATE_SIZE: u64 = 1024 * 1024;
+const KEEP_FILES: usize = 4;
+
+static HANDLE: OnceLock<LoggerHandle> = OnceLock::new();
+
+/// Start logging. The tray rotates the file and copies warnings and errors to stderr,
+/// terminal commands only append to it (they print their own errors, and rotating is left to the tray)
+pub fn start(tray: bool) {
+    let (spec, bad_env) = starting_level(std::env::var(LEVEL_VAR).ok().as_deref());
+
+    let mut logger = Logger::with(spec.clone()).format(format);
+    match config::state_dir() {
+        Some(dir) => {
+            let file = FileSpec::default().directory(dir).basename("pihole-switch").suppress_timestamp();
+            logger = if tray {
+                logger.log_to_file(file)
+                    .rotate(Criterion::Size(ROTATE_SIZE), Naming::Numbers, Cleanup::KeepLogFiles(KEEP_FILES))
+                    .duplicate_to_stderr(Duplicate::Warn)
+            } else {
+                // Same file the tray is writing to, it names the current one _rCURRENT while rotating
+                logger.log_to_file(file.discriminant("rCURRENT"))
+            };
+            logger = logger.append();
+        }
+        None => logger = logger.log_to_stderr(),
+    }
+
+    let handle = match logger.start() {
+        Ok(handle) => handle,
+        Err(e) => {
+            // Can't write the file (read only home, ...). Logging to stderr still beats nothing
+            eprintln!("Could not start logging to {}: {}", config::state_dir().map(|dir| dir.display().to_string()).unwrap_or_default(), e);
+            match Logger::with(spec).format(format).log_to_stderr().start() {
+                Ok(handle) => handle,
+                Err(_) => return,
+            }
+        }
+    };
+    let _ = HANDLE.set(handle);
+
+    if let Some(e) = bad_env {
+        // Terminal commands don't copy warnings to stderr, but this one should be seen
+        if !tray {
+            eprintln!("{}", e);
+        }
+        log_warn!(e);
+    }
+}
+
+/// Change the level while running
+pub fn set_level(level: &str) -> Result<(), String> {
+    let spec = parse(level).map_err(|e| format!("Invalid log level \"{}\": {}", level, e))?;
+    match HANDLE.get() {
+        Some(handle) => {
+            handle.set_new_spec(spec);
+            Ok(())
+        }
+        None => Err("Logging is not running".to_string()),
+    }
+}
+
+/// Use the level from the config, unless PIHOLE_SWITCH_LOG overrides it
+pub fn use_config_level(level: Option<&str>) {
+    if std::env::var_os(LEVEL_VAR).is_some() {
+        return;
+    }
+    if let Some(level) = level {
+        if let Err(e) = set_level(level) {
+            log_warn!(e);
+        }
+    }
+}
+
+// Level to start with from the value of PIHOLE_SWITCH_LOG. A bad value falls back to the default, with the warning to show
+fn starting_level(value: Option<&str>) -> (LogSpecification, Option<String>) {
+    match value {
+        Some(value) => match parse(value) {
+            Ok(spec) => (spec, None),
+            Err(e) => (parse(DEFAULT_LEVEL).unwrap(), Some(format!("Invalid {}: {}", LEVEL_VAR, e))),
+        },
+        None => (parse(DEFAULT_LEVEL).unwrap(), None),
+    }
+}
+
+// Parse a level ("debug") or env_logger style spec ("info, reqwest=debug")
+fn parse(level: &str) -> Result<LogSpecification, String> {
+    // A single word has to be a level, "debgu" would otherwise quietly be read as a module name
+    for part in level.split(',').map(str::trim).filter(|part| !part.is_empty() && !part.contains('=')) {
+        if part.parse::<log::LevelFilter>().is_err() {
+            return Err(format!("unknown level \"{}\" (expected off, error, warn, info, debug or trace)", part));
+        }
+    }
+    LogSpecification::parse(level).map_err(|e| e.to_string())
+}
+
+// [LEVEL][MM-DD-YYYY hh:mm:ss] message, warnings and errors get a "Warning:"/"Error:" in front
+fn format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+    let prefix = match record.level() {
+        Level::Warn => "Warning: ",
+        Level::Error => "Error: ",
+        _ => "",
+    };
+    write!(w, "[{}][{}] {}{}", record.level(), now.format("%m-%d-%Y %H:%M:%S"), prefix, record.args())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flexi_logger::ModuleFilter;
+    use log::LevelFilter;
+
+    fn filters(level: &str) -> Vec<ModuleFilter> {
+        parse(level).unwrap().module_filters().clone()
+    }
+
+    fn default(level: LevelFilter) -> ModuleFilter {
+        ModuleFilter {module_name: None, level_filter: level}
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!(filters("off"), [default(LevelFilter::Off)]);
+        assert_eq!(filters("error"), [default(LevelFilter::Error)]);
+        assert_eq!(filters("warn"), [default(LevelFilter::Warn)]);
+        assert_eq!(filters("info"), [default(LevelFilter::Info)]);
+        assert_eq!(filters("debug"), [default(LevelFilter::Debug)]);
+        assert_eq!(filters("trace"), [default(LevelFilter::Trace)]);
+
+        let module = filters("info, reqwest=debug");
+        assert!(module.contains(&default(LevelFilter::Info)));
+        assert!(module.contains(&ModuleFilter {module_name: Some("reqwest".to_string()), level_filter: LevelFilter::Debug}));
+    }
+
+    #[test]
+    fn levels_ignore_case() {
+        assert_eq!(filters("DEBUG"), [default(LevelFilter::Debug)]);
+        assert_eq!(filters("Warn"), [default(LevelFilter::Warn)]);
+        assert_eq!(filters(" trace "), [default(LevelFilter::Trace)]);
+    }
+
+    #[test]
+    fn bad_levels() {
+        assert!(parse("debgu").is_err());
+        assert!(parse("info, loud").is_err());
+        assert!(set_level("verbose").is_err());
+
+        // A bad PIHOLE_SWITCH_LOG starts at the default level, saying why
+        let (spec, warning) = starting_level(Some("debgu"));
+        assert_eq!(spec.module_filters(), &[default(LevelFilter::Info)]);
+        assert!(warning.unwrap().starts_with("Invalid PIHOLE_SWITCH_LOG: unknown level \"debgu\""));
+
+        let (spec, warning) = starting_level(None);
+        assert_eq!(spec.module_filters(), &[default(LevelFilter::Info)]);
+        assert_eq!(warning, None);
+        assert_eq!(starting_level(Some("trace")).0.module_filters(), &[default(LevelFilter::Trace)]);
+    }
+}
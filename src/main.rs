@@ -3,10 +3,19 @@
 // todo: Figure out doing this for linux
 
 
-use dotenv::from_path;
 pub mod tray_functions;
 pub mod tray_handler;
 pub mod piapi_handler;
+pub mod backend;
+pub mod pihole_group;
+pub mod config;
+pub mod poller;
+pub mod credentials;
+pub mod cli;
+pub mod logging;
+pub mod history;
+pub mod temporary;
+pub mod querylog;
 
 #[cfg(target_os = "windows")]
 pub mod windows;
@@ -14,101 +23,125 @@ pub mod windows;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
+#[cfg(target_os = "linux")]
+pub mod dialogs;
+
+#[cfg(target_os = "linux")]
+pub mod instance;
+
+#[cfg(target_os = "linux")]
+pub mod dbus;
+
+#[cfg(target_os = "linux")]
+pub mod notifications;
+
+#[cfg(target_os = "linux")]
+pub mod querylog_window;
+
 #[cfg(target_os = "linux")]
 mod icons {
     pub const BLANK_ICON: &[u8] = include_bytes!("../resources/Pi-hole_blank.ico");
     pub const DISABLED_ICON: &[u8] = include_bytes!("../resources/Pi-hole_disabled.ico");
     pub const ENABLED_ICON: &[u8] = include_bytes!("../resources/Pi-hole_enabled.ico");
+    pub const MIXED_ICON: &[u8] = include_bytes!("../resources/Pi-hole_mixed.ico");
+    pub const UNREACHABLE_ICON: &[u8] = include_bytes!("../resources/Pi-hole_unreachable.ico");
 }
 
 #[cfg(target_os = "linux")]
 use icons::*;
 
-// Create general logging message macro
-#[macro_export]
-macro_rules! log_message {
-    ($level:expr, $msg:expr) => {{
-        use std::fs::OpenOptions;
-        use std::io::Write;
-        use chrono::Local;
-
-        // Format timestamp MM-DD-YYYY hh:mm:ss
-        let timestamp = Local::now().format("%m-%d-%Y %H:%M:%S").to_string();
-        // Format string
-        let log_entry = format!("[{}][{}] {} {}\n", $level, timestamp, if ($level == "WARN") {"Warning:"} else {if ($level == "ERROR") {"Error:"} else {""}},$msg);
-
-        // Open or create the log file
-        // If it doesn't exist, create it. then, append to it
-        let mut file = OpenOptions::new()
-            .create(true)  
-            .append(true)  
-            .open("output.log")
-            .expect("Failed to open log file");
-
-        // Write the log entry to the file
-        file.write_all(log_entry.as_bytes())
-            .expect("Failed to write to log file");
-    }};
-}
-
-// Define other macros for specific log levels
+// Logging shortcuts, see logging.rs for where the messages end up
 #[macro_export]
 macro_rules! log_info {
     ($msg:expr) => {
-        log_message!("INFO", $msg);
+        log::info!("{}", $msg)
     };
 }
 
 #[macro_export]
 macro_rules! log_warn {
     ($msg:expr) => {
-        log_message!("WARN", $msg);
+        log::warn!("{}", $msg)
     };
 }
 
 #[macro_export]
 macro_rules! log_err {
     ($msg:expr) => {
-        log_message!("ERROR", $msg);
+        log::error!("{}", $msg)
     };
 }
 
-fn get_cargo_root() -> Option<std::path::PathBuf> {
-    let mut exe_path = std::env::current_exe().ok()?;
+// #[tokio::main]
+fn main() {
 
-    // Go up the directory tree from the executable's location
-    while !exe_path.join(".env").exists() {
-        if !exe_path.pop() {
-            return None; // reached the root without finding .env
+    let args = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
         }
-    }
+    };
 
-    // Return the path with .env appended
-    Some(exe_path.join(".env"))
-}
+    // Commands run in the terminal instead of starting the tray
+    if !args.command.is_empty() {
+        logging::start(false);
+
+        // Let the running tray do it, so its menu and countdown stay in sync.
+        // Not with --config, the tray may be using other piholes than the ones asked for
+        #[cfg(target_os = "linux")]
+        if let (None, Ok(Some(_))) = (&args.config, cli::parse_action(&args.command)) {
+            if let Some(reply) = instance::forward(&args.command, args.json) {
+                reply.print();
+                std::process::exit(reply.code);
+            }
+        }
 
-// #[tokio::main]
-fn main() {
+        std::process::exit(cli::run(&args));
+    }
+
+    logging::start(true);
 
-    // Store the result in a variable to extend the lifetime
-    let cargo_root = get_cargo_root()
-        .expect("Could not find env");
+    // Only one tray at a time
+    #[cfg(target_os = "linux")]
+    let listener = match instance::acquire() {
+        Ok(Some(listener)) => Some(listener),
+        Ok(None) => {
+            println!("pihole-switch is already running");
+            return;
+        }
+        Err(e) => {
+            // Still start, the tray is more useful than the lock
+            log_err!(format!("Could not create the instance socket: {}", e));
+            None
+        }
+    };
 
-    // Now use it to create the env_path
-    let env_path = std::path::Path::new(
-        cargo_root.to_str()
-            .expect("Path contains invalid Unicode")
-    );
+    // Load and validate the settings. Nothing can run without them so log why and quit
+    let config = match config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            log_err!(e);
+            std::process::exit(1);
+        }
+    };
+    logging::use_config_level(config.log_level.as_deref());
+
+    // Create an api handler for each pihole, with its key from the keyring
+    let pi_api = match pihole_group::PiHoleGroup::from_config(config.piholes, credentials::default_store().as_ref()) {
+        Ok(group) => group,
+        Err(e) => {
+            log_err!(e);
+            std::process::exit(1);
+        }
+    };
+    let presets = config.presets;
 
-    from_path(env_path).expect("Failed to load .env file");
-    // Prep retrieval of environment variables
-    // dotenv().ok();
+    // Status checks run in the background from here on
+    let (poller, status_rx) = poller::start(pi_api.clone(), std::time::Duration::from_secs(config.poll_interval));
 
-    // Retrieve env variables and create the api handler
-    let pi_api = piapi_handler::AuthPiHoleAPI::new(
-        std::env::var("PI_HOLE_ADDR").expect("PI_HOLE_ADDR must be set").clone(),
-        std::env::var("PI_HOLE_KEY").expect("PI_HOLE_KEY must be set").clone(),
-    );
+    // Take temporarily allowed domains off the allowlist again, also the ones that ran out while closed
+    temporary::start(pi_api.clone());
 
     // If unable to initialize GTK then the app cannot run anyway. Submit log and quit
     // Must do this before pi_tray is created as it will cause rust to panic
@@ -126,13 +159,31 @@ fn main() {
     #[cfg(not(debug_assertions))]
     let pi_tray = tray_handler::TrayIcon::new("Pi-Hole", 2); 
     
+    // Take commands from other launches
+    #[cfg(target_os = "linux")]
+    let holds_lock = listener.is_some();
+    #[cfg(target_os = "linux")]
+    if let Some(listener) = listener {
+        instance::serve(listener, pi_api.clone(), pi_tray.timer(), poller.clone());
+    }
+
     // infinite loop to keep app from dying
     #[cfg(target_os = "linux")]  // LINUX mainloop
-    linux::main(pi_api, pi_tray);
+    linux::main(pi_api.clone(), pi_tray, presets, poller, status_rx, config.notifications);
     
 
     #[cfg(target_os = "windows")] // WINDOWS mainloop
-    windows::main(pi_api, pi_tray);
+    windows::main(pi_api.clone(), pi_tray, presets, poller, status_rx);
+
+    // Free the session slots on the piholes (v6 only)
+    for instance in pi_api.instances() {
+        block_on!(async {instance.api.logout().await});
+    }
+
+    #[cfg(target_os = "linux")]
+    if holds_lock {
+        instance::release();
+    }
 
     log_warn!("Loop exited program ending");
 }
@@ -0,0 +1,199 @@
+/*
+    Desktop notifications (org.freedesktop.Notifications on the session bus) when the tray sees the state change:
+    blocking resumed after a timed disable, disabled from the web interface, pihole gone or back.
+    Also when a gravity update started from the tray is done.
+    Sending happens on its own thread, the notification daemon can be slow to answer (or start)
+ */
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::Instant;
+use zbus::zvariant::Value;
+use crate::{config, log_err, log_warn};
+use crate::piapi_handler::PiHoleError;
+use crate::pihole_group::GroupState;
+use crate::poller::Poller;
+
+const APP_NAME: &str = "Pi-hole Switch";
+
+// A notification waiting to be sent
+struct Message {
+    summary: String,
+    body: String,
+    icon: &'static str,
+}
+
+/// Sends the notifications for state changes that are turned on in the config
+#[derive(Clone)]
+pub struct Notifier {
+    events: config::Notifications,
+    // Tells changes made from the menu, CLI or D-Bus apart from the rest
+    poller: Poller,
+    tx: mpsc::Sender<Message>,
+}
+
+/// Connect to the session bus and start the sending thread
+pub fn start(events: config::Notifications, poller: Poller) -> zbus::Result<Notifier> {
+    Ok(start_on(zbus::blocking::Connection::session()?, events, poller))
+}
+
+// Start the sending thread on the given bus (the session bus, or a private one in the tests)
+fn start_on(connection: zbus::blocking::Connection, events: config::Notifications, poller: Poller) -> Notifier {
+    let (tx, rx) = mpsc::channel::<Message>();
+
+    std::thread::spawn(move || {
+        // Each notification replaces the last one, so "unreachable" is swapped for "reachable again" instead of piling up
+        let mut id = 0;
+        for message in rx {
+            match send(&connection, id, &message) {
+                Ok(new_id) => id = new_id,
+                Err(e) => log_warn!(format!("Error sending notification: {}", e)),
+            }
+        }
+    });
+
+    Notifier {events, poller, tx}
+}
+
+// Call Notify, returns the id of the notification
+fn send(connection: &zbus::blocking::Connection, replaces_id: u32, message: &Message) -> zbus::Result<u32> {
+    let actions: Vec<&str> = Vec::new();
+    let hints: HashMap<&str, Value> = HashMap::new();
+
+    let reply = connection.call_method(
+        Some("org.freedesktop.Notifications"),
+        "/org/freedesktop/Notifications",
+        Some("org.freedesktop.Notifications"),
+        "Notify",
+        // app name, replaces id, icon, summary, body, actions, hints, timeout (-1 is the server default)
+        &(APP_NAME, replaces_id, message.icon, message.summary.as_str(), message.body.as_str(), actions, hints, -1i32),
+    )?;
+
+    reply.body().deserialize::<u32>()
+}
+
+impl Notifier {
+    /// Notify about the tray going from old to new, when that event is turned on.
+    /// since is when the poll that showed the old state was sent, body the status text the tray shows now
+    pub fn state_changed(&self, old: GroupState, new: GroupState, since: Option<Instant>, body: &str) {
+        use GroupState::*;
+
+        // Every action pokes the poller once done. A poke since the old state was seen means the user
+        // just did this themselves and doesn't need telling (losing or finding the pihole is still news)
+        let own_action = since.is_some_and(|since| self.poller.poked_since(since));
+
+        let (enabled, summary, icon) = match (old, new) {
+            // Nothing to tell about the first status check, unless the pihole can't be reached
+            (_, Unreachable) => (self.events.unreachable, "Pi-hole unreachable", "network-error"),
+            (Unreachable, Enabled | Mixed | Disabled) => (self.events.reachable, "Pi-hole reachable again", "network-idle"),
+            (Mixed | Disabled, Enabled) => (self.events.resumed && !own_action, "Pi-hole blocking resumed", "security-high"),
+            (Disabled, Mixed) => (self.events.resumed && !own_action, "Pi-hole blocking partly resumed", "security-medium"),
+            (Enabled, Mixed) => (self.events.disabled && !own_action, "Pi-hole blocking partly disabled", "security-medium"),
+            (Enabled | Mixed, Disabled) => (self.events.disabled && !own_action, "Pi-hole blocking disabled", "security-low"),
+            _ => return,
+        };
+
+        if !enabled {
+            return;
+        }
+
+        let message = Message {summary: summary.to_string(), body: body.to_string(), icon};
+        if self.tx.send(message).is_err() {
+            log_err!("Error sending notification: sending thread is gone");
+        }
+    }
+
+    /// Notify that a gravity update finished or failed, when that event is turned on
+    pub fn gravity_done(&self, result: &Result<(), PiHoleError>) {
+        if !self.events.gravity {
+            return;
+        }
+
+        let message = match result {
+            Ok(()) => Message {summary: "Gravity updated".to_string(), body: "The blocklists were downloaded again".to_string(), icon: "emblem-ok-symbolic"},
+            Err(e) => Message {summary: "Gravity update failed".to_string(), body: e.to_string(), icon: "dialog-error"},
+        };
+        if self.tx.send(message).is_err() {
+            log_err!("Error sending notification: sending thread is gone");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use zbus::interface;
+    use zbus::zvariant::OwnedValue;
+    use crate::backend::FakePiHole;
+    use crate::dbus::tests::PrivateBus;
+    use crate::pihole_group::{Instance, PiHoleGroup};
+    use crate::poller;
+
+    // (replaces id, summary, body) of every Notify call
+    type Sent = Arc<Mutex<Vec<(u32, String, String)>>>;
+
+    // Stands in for the desktop's notification daemon
+    struct MockNotifications {
+        sent: Sent,
+    }
+
+    #[interface(name = "org.freedesktop.Notifications")]
+    impl MockNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(&self, _app_name: &str, replaces_id: u32, _icon: &str, summary: &str, body: &str,
+                  _actions: Vec<String>, _hints: HashMap<String, OwnedValue>, _timeout: i32) -> u32 {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push((replaces_id, summary.to_string(), body.to_string()));
+            sent.len() as u32
+        }
+    }
+
+    // Wait for the sending thread to get through count notifications
+    fn wait_for(sent: &Sent, count: usize) -> Vec<(u32, String, String)> {
+        for _ in 0..100 {
+            if sent.lock().unwrap().len() >= count {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        sent.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn notifies_on_state_changes() {
+        let bus = PrivateBus::start().expect("dbus-daemon is needed for the notification tests");
+
+        let sent = Sent::default();
+        let _daemon = bus.connect()
+            .name("org.freedesktop.Notifications").unwrap()
+            .serve_at("/org/freedesktop/Notifications", MockNotifications {sent: sent.clone()}).unwrap()
+            .build().unwrap();
+
+        let group = PiHoleGroup::new(vec![Instance {name: "fake".to_string(), api: FakePiHole::new(true)}]);
+        let (poller, _polls) = poller::start(group, Duration::from_secs(60));
+        let events = config::Notifications {reachable: false, ..Default::default()};
+        let notifier = start_on(bus.connect().build().unwrap(), events, poller.clone());
+
+        // Disabled in the web interface
+        notifier.state_changed(GroupState::Enabled, GroupState::Disabled, Some(Instant::now()), "Disabled");
+        assert_eq!(wait_for(&sent, 1), [(0, "Pi-hole blocking disabled".to_string(), "Disabled".to_string())]);
+
+        // Enabled from the menu (poked after the last poll), nothing to tell
+        let since = Instant::now();
+        poller.poll_now();
+        notifier.state_changed(GroupState::Disabled, GroupState::Enabled, Some(since), "Enabled");
+        // Turned off in the config
+        notifier.state_changed(GroupState::Unreachable, GroupState::Enabled, None, "Enabled");
+
+        // Replaces the last notification
+        notifier.state_changed(GroupState::Enabled, GroupState::Unreachable, None, "No answer");
+        notifier.gravity_done(&Err(PiHoleError::Refused("No space left".to_string())));
+
+        let sent = wait_for(&sent, 3);
+        assert_eq!(sent[1..], [
+            (1, "Pi-hole unreachable".to_string(), "No answer".to_string()),
+            (2, "Gravity update failed".to_string(), "Refused by pihole: No space left".to_string()),
+        ]);
+    }
+}
@@ -1,87 +1,1315 @@
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use async_trait::async_trait;
+use crate::backend::{PiHoleBackend, Progress};
+use crate::{log_err, log_warn};
+
+/// Longest a single request to a pihole may take
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest to wait for the connection itself (a host that is down)
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Longest a gravity update may take, it downloads every blocklist again
+pub const GRAVITY_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Number of queries fetched the first time the query log is read
+pub const FIRST_QUERIES: usize = 100;
+
+/// Which flavour of the Pi-hole API a host speaks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ApiVersion {
+    /// Pi-hole v5 and older (`/admin/api.php?...&auth=`)
+    Legacy,
+    /// Pi-hole v6 REST API (`/api/...` with a session id)
+    V6,
+}
+
+/// Errors returned when talking to a pihole
+#[derive(Clone, Debug, PartialEq)]
+pub enum PiHoleError {
+    /// Could not connect to the pihole (down, wrong address, no network)
+    Unreachable(String),
+    /// The pihole refused the api key or session
+    AuthRejected(String),
+    /// The pihole answered with something that could not be understood
+    UnexpectedPayload(String),
+    /// The pihole did not answer in time
+    Timeout(String),
+    /// The pihole turned the request down (ex. an invalid regex, a domain that isn't on the list)
+    Refused(String),
+}
+
+impl std::fmt::Display for PiHoleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PiHoleError::Unreachable(msg) => write!(f, "Pihole unreachable: {}", msg),
+            PiHoleError::AuthRejected(msg) => write!(f, "Authentication rejected: {}", msg),
+            PiHoleError::UnexpectedPayload(msg) => write!(f, "Unexpected response: {}", msg),
+            PiHoleError::Timeout(msg) => write!(f, "Request timed out: {}", msg),
+            PiHoleError::Refused(msg) => write!(f, "Refused by pihole: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PiHoleError {}
+
+impl PiHoleError {
+    /// Prefix the message with the name of the pihole it came from, keeping the kind
+    pub fn for_host(self, name: &str) -> Self {
+        match self {
+            PiHoleError::Unreachable(msg) => PiHoleError::Unreachable(format!("{}: {}", name, msg)),
+            PiHoleError::AuthRejected(msg) => PiHoleError::AuthRejected(format!("{}: {}", name, msg)),
+            PiHoleError::UnexpectedPayload(msg) => PiHoleError::UnexpectedPayload(format!("{}: {}", name, msg)),
+            PiHoleError::Timeout(msg) => PiHoleError::Timeout(format!("{}: {}", name, msg)),
+            PiHoleError::Refused(msg) => PiHoleError::Refused(format!("{}: {}", name, msg)),
+        }
+    }
+}
+
+impl From<reqwest::Error> for PiHoleError {
+    fn from(e: reqwest::Error) -> Self {
+        // Sort the reqwest error into the matching kind
+        if e.is_timeout() {
+            PiHoleError::Timeout(e.to_string())
+        } else if let Some(status) = e.status() {
+            if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+                PiHoleError::AuthRejected(e.to_string())
+            } else {
+                PiHoleError::UnexpectedPayload(e.to_string())
+            }
+        } else if e.is_decode() {
+            PiHoleError::UnexpectedPayload(e.to_string())
+        } else {
+            PiHoleError::Unreachable(e.to_string())
+        }
+    }
+}
+
+/// Blocking state reported by the pihole
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockingStatus {
+    /// True when ads are being blocked
+    pub enabled: bool,
+    /// Seconds until blocking is enabled again, if disabled with a timer (v6 only)
+    pub timer_remaining: Option<u64>,
+}
+
+/// Numbers of the day, as shown on the dashboard
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// DNS queries answered today
+    pub queries_today: u64,
+    /// Queries blocked today
+    pub blocked_today: u64,
+    /// Share of today's queries that were blocked, 0 to 100
+    pub percent_blocked: f64,
+    /// Domains on the blocklists (gravity)
+    pub domains_on_blocklist: u64,
+    /// Clients that sent queries
+    pub clients: u64,
+}
+
+/// One of the domain lists. Exact entries match the domain itself, regex entries any domain matching the pattern
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DomainList {
+    Allow,
+    AllowRegex,
+    Deny,
+    DenyRegex,
+}
+
+impl DomainList {
+    /// Every list, allow before deny
+    pub const ALL: [DomainList; 4] = [DomainList::Allow, DomainList::AllowRegex, DomainList::Deny, DomainList::DenyRegex];
+
+    /// The allow or deny list, exact or regex
+    pub fn new(allow: bool, regex: bool) -> Self {
+        match (allow, regex) {
+            (true, false) => DomainList::Allow,
+            (true, true) => DomainList::AllowRegex,
+            (false, false) => DomainList::Deny,
+            (false, true) => DomainList::DenyRegex,
+        }
+    }
+
+    /// Short name used in JSON output ("allow", "allow-regex", ...)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DomainList::Allow => "allow",
+            DomainList::AllowRegex => "allow-regex",
+            DomainList::Deny => "deny",
+            DomainList::DenyRegex => "deny-regex",
+        }
+    }
+
+    /// Name for messages, ex. "regex allowlist"
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DomainList::Allow => "allowlist",
+            DomainList::AllowRegex => "regex allowlist",
+            DomainList::Deny => "denylist",
+            DomainList::DenyRegex => "regex denylist",
+        }
+    }
+
+    // Value of list= in the legacy api
+    fn legacy_name(&self) -> &'static str {
+        match self {
+            DomainList::Allow => "white",
+            DomainList::AllowRegex => "regex_white",
+            DomainList::Deny => "black",
+            DomainList::DenyRegex => "regex_black",
+        }
+    }
+
+    // Path under /api/domains in the v6 api
+    fn v6_path(&self) -> &'static str {
+        match self {
+            DomainList::Allow => "allow/exact",
+            DomainList::AllowRegex => "allow/regex",
+            DomainList::Deny => "deny/exact",
+            DomainList::DenyRegex => "deny/regex",
+        }
+    }
+}
+
+/// An entry on one of the domain lists
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ListedDomain {
+    /// The domain, or the pattern on regex lists
+    pub domain: String,
+    /// Disabled entries stay on the list but don't match anything
+    pub enabled: bool,
+    pub comment: Option<String>,
+}
+
+/// Where a search found a domain
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchSource {
+    /// One of the allow/deny lists, exact or regex
+    List(DomainList),
+    /// A subscribed blocklist (adlist), by its address
+    Blocklist(String),
+    /// A subscribed allowlist (v6 only), by its address
+    AllowSubscription(String),
+}
+
+impl MatchSource {
+    /// True when a match here lets the domain through
+    pub fn allows(&self) -> bool {
+        matches!(self, MatchSource::List(DomainList::Allow | DomainList::AllowRegex) | MatchSource::AllowSubscription(_))
+    }
+
+    /// Name for messages, ex. "denylist" or "blocklist https://..."
+    pub fn describe(&self) -> String {
+        match self {
+            MatchSource::List(list) => list.describe().to_string(),
+            MatchSource::Blocklist(address) => format!("blocklist {}", address),
+            MatchSource::AllowSubscription(address) => format!("allowlist subscription {}", address),
+        }
+    }
+}
+
+/// One list entry matching a searched domain
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchMatch {
+    pub source: MatchSource,
+    /// The entry that matched: the domain itself, or the regex
+    pub entry: String,
+    /// Disabled entries don't count
+    pub enabled: bool,
+}
+
+/// Every list entry matching a domain
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub domain: String,
+    pub matches: Vec<SearchMatch>,
+}
+
+impl SearchResult {
+    /// Whether the domain gets blocked. The allowlists win over everything else, like on the pihole itself
+    pub fn blocked(&self) -> bool {
+        let enabled = || self.matches.iter().filter(|found| found.enabled);
+        !enabled().any(|found| found.source.allows()) && enabled().any(|found| !found.source.allows())
+    }
+}
+
+/// One DNS query from the query log
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Query {
+    /// Unix time, with fractions of a second on v6
+    pub time: f64,
+    /// Record type, ex. "A", "AAAA", "HTTPS"
+    #[serde(rename = "type")]
+    pub query_type: String,
+    pub domain: String,
+    /// Address of the client, with its name after it when the pihole knows it ("192.168.1.5 (laptop)")
+    pub client: String,
+    /// What the pihole did with it, ex. "GRAVITY", "FORWARDED", "CACHE". Legacy codes get the v6 name
+    pub status: String,
+    pub blocked: bool,
+}
+
+/// Where the last read of the query log stopped, so the next read only returns newer queries
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryCursor {
+    /// Time of the newest query seen
+    pub time: f64,
+    /// v6: id of the newest query seen
+    pub last_id: u64,
+    /// Legacy: the queries seen in the second of `time` (that's all the precision it has), to skip them next time
+    pub seen: Vec<String>,
+}
+
+// Statuses that mean the query was blocked, by v6 name
+fn blocked_status(status: &str) -> bool {
+    matches!(status, "GRAVITY" | "REGEX" | "DENYLIST" | "GRAVITY_CNAME" | "REGEX_CNAME" | "DENYLIST_CNAME" | "DBBUSY" | "SPECIAL_DOMAIN")
+        || status.starts_with("EXTERNAL_BLOCKED")
+}
+
+// v6 name of a legacy status code
+fn legacy_status(code: u64) -> &'static str {
+    match code {
+        1 => "GRAVITY",
+        2 => "FORWARDED",
+        3 => "CACHE",
+        4 => "REGEX",
+        5 => "DENYLIST",
+        6 => "EXTERNAL_BLOCKED_IP",
+        7 => "EXTERNAL_BLOCKED_NULL",
+        8 => "EXTERNAL_BLOCKED_NXRA",
+        9 => "GRAVITY_CNAME",
+        10 => "REGEX_CNAME",
+        11 => "DENYLIST_CNAME",
+        12 => "RETRIED",
+        13 => "RETRIED_DNSSEC",
+        14 => "IN_PROGRESS",
+        15 => "DBBUSY",
+        16 => "SPECIAL_DOMAIN",
+        17 => "CACHE_STALE",
+        _ => "UNKNOWN",
+    }
+}
+
+// Session handed out by the v6 api after logging in
+struct Session {
+    sid: String,
+    validity: Duration,
+    expires: Instant,
+}
+
+// Body of the v6 /api/auth responses
+#[derive(Deserialize)]
+struct AuthResponse {
+    session: AuthSession,
+}
+
+#[derive(Deserialize)]
+struct AuthSession {
+    valid: bool,
+    sid: Option<String>,
+    validity: i64,
+    message: Option<String>,
+}
+
+// Body of the v6 /api/dns/blocking responses
+#[derive(Deserialize)]
+struct BlockingResponse {
+    blocking: String,
+    timer: Option<f64>,
+}
+
+// Body of the legacy api.php?summaryRaw response (only the fields used)
+#[derive(Deserialize)]
+struct LegacySummary {
+    dns_queries_today: u64,
+    ads_blocked_today: u64,
+    ads_percentage_today: f64,
+    domains_being_blocked: u64,
+    unique_clients: u64,
+}
+
+// Body of the v6 /api/stats/summary response (only the fields used)
+#[derive(Deserialize)]
+struct SummaryResponse {
+    queries: SummaryQueries,
+    clients: SummaryClients,
+    gravity: SummaryGravity,
+}
+
+#[derive(Deserialize)]
+struct SummaryQueries {
+    total: u64,
+    blocked: u64,
+    percent_blocked: f64,
+}
+
+#[derive(Deserialize)]
+struct SummaryClients {
+    active: u64,
+}
+
+#[derive(Deserialize)]
+struct SummaryGravity {
+    // -1 while gravity couldn't be read
+    domains_being_blocked: i64,
+}
+
+// Body of the v6 /api/domains responses (only the fields used)
+#[derive(Deserialize)]
+struct DomainsResponse {
+    domains: Vec<DomainsEntry>,
+}
+
+#[derive(Deserialize)]
+struct DomainsEntry {
+    domain: String,
+    enabled: bool,
+    comment: Option<String>,
+}
+
+// Body of the v6 /api/search response (only the fields used)
+#[derive(Deserialize)]
+struct SearchResponse {
+    search: SearchBody,
+}
+
+#[derive(Deserialize)]
+struct SearchBody {
+    domains: Vec<SearchDomain>,
+    gravity: Vec<SearchGravity>,
+}
+
+// A match on the allow/deny lists, type is "allow"/"deny" and kind "exact"/"regex"
+#[derive(Deserialize)]
+struct SearchDomain {
+    domain: String,
+    #[serde(rename = "type")]
+    list_type: String,
+    kind: String,
+    enabled: bool,
+}
+
+// A match on a subscribed list, type is "block" or "allow"
+#[derive(Deserialize)]
+struct SearchGravity {
+    domain: String,
+    address: String,
+    #[serde(rename = "type")]
+    list_type: String,
+    enabled: bool,
+}
+
+// Body of the v6 /api/queries response (only the fields used), newest query first
+#[derive(Deserialize)]
+struct QueriesResponse {
+    queries: Vec<QueryEntry>,
+}
+
+#[derive(Deserialize)]
+struct QueryEntry {
+    id: u64,
+    time: f64,
+    #[serde(rename = "type")]
+    query_type: Option<String>,
+    domain: String,
+    client: QueryClient,
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QueryClient {
+    ip: String,
+    name: Option<String>,
+}
+
+// Body of a v6 error response, ex. {"error": {"key": "bad_request", "message": "Invalid regex", "hint": "..."}}
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+    hint: Option<String>,
+}
 
 #[derive(Clone)]
 pub struct AuthPiHoleAPI {
     host: String,
     key: String,
     client: reqwest::Client,
+    // Shared between clones so every tray button uses the same detection result and session
+    version: Arc<Mutex<Option<ApiVersion>>>,
+    session: Arc<tokio::sync::Mutex<Option<Session>>>,
 }
 
 impl AuthPiHoleAPI {
     /// Create new AuthPiHoleAPI
+    /// The api version is detected on the first call
     pub fn new(host: String, key: String) -> Self {
         // Create client here to prevent opening a new connection each time
-        let client = reqwest::Client::new();
-        Self {host, key, client}
+        // A pihole that stops answering fails after REQUEST_TIMEOUT instead of hanging the request forever
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build the http client");
+        let version = Arc::new(Mutex::new(None));
+        let session = Arc::new(tokio::sync::Mutex::new(None));
+        Self {host, key, client, version, session}
     }
 
-    /// Open the dashboard in the default browser
-    pub fn open_dashboard(&self) {
-        // Format address string
-        let addr = format!("{}/admin", self.host);
+    /// Find out which api the host speaks. The result is cached after the first successful check
+    pub async fn api_version(&self) -> Result<ApiVersion, PiHoleError> {
+        if let Some(version) = *self.version.lock().unwrap() {
+            return Ok(version);
+        }
 
-        // Open the address
-        match open::that(addr) {
-            Ok(_) => {}
-            Err(e) => {eprintln!("Error in open: {}", e);}
+        // v6 always answers /api/auth with a session object (even when not logged in)
+        // Older installs answer with a 404 page instead
+        let resp = self.client.get(format!("{}/api/auth", self.host)).send().await?;
+        let version = match resp.json::<AuthResponse>().await {
+            Ok(_) => ApiVersion::V6,
+            Err(_) => ApiVersion::Legacy,
+        };
+
+        *self.version.lock().unwrap() = Some(version);
+        Ok(version)
+    }
+
+    /// End the v6 session so it doesn't take up one of the limited session slots on the pihole
+    pub async fn logout(&self) {
+        let mut session = self.session.lock().await;
+
+        if let Some(current) = session.take() {
+            let result = self.client.delete(format!("{}/api/auth", self.host))
+                .header("X-FTL-SID", current.sid)
+                .send()
+                .await;
+
+            if let Err(e) = result {
+                log_warn!(format!("Error ending session: {}", e));
+            }
         }
     }
 
-    /// Disable pihole for n seconds
-    pub async fn disable(&self, seconds: u64) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    // Set the v6 blocking state
+    async fn set_blocking(&self, blocking: bool, timer: serde_json::Value) -> Result<BlockingStatus, PiHoleError> {
+        let body = json!({"blocking": blocking, "timer": timer});
+
+        let resp = self.v6_request(reqwest::Method::POST, "dns/blocking", Some(body)).await?;
+        resp.json::<BlockingResponse>().await?.into_status()
+    }
+
+    // Call a legacy api.php action and read the status out of the response
+    async fn legacy_request(&self, action: &str) -> Result<BlockingStatus, PiHoleError> {
+        parse_legacy_status(&self.legacy_call(action).await?)
+    }
+
+    // Call a legacy api.php action and return the raw json
+    async fn legacy_call(&self, action: &str) -> Result<serde_json::Value, PiHoleError> {
         // Format the url
-        let url = format!("{}/admin/api.php?disable{}&auth={}",
+        let url = format!("{}/admin/api.php?{}&auth={}",
             self.host,
-            (if seconds != 0 {format!("={}", seconds)} else {"".to_string()}),
+            action,
             self.key
         );
-    
-        // Call api
+
+        // Call the api
         let resp = self.client.get(url).send()
             .await?
-            .json::<HashMap<String, String>>()
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        check_legacy_reply(resp)
+    }
+
+    // Log in with the api key (app password) and return the new session
+    async fn login(&self) -> Result<Session, PiHoleError> {
+        let resp = self.client.post(format!("{}/api/auth", self.host))
+            .json(&json!({"password": self.key}))
+            .send()
             .await?;
-        
-        // Pass the response back
-        Ok(resp)
+
+        if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(PiHoleError::AuthRejected("Password not accepted by pihole, check the api key".to_string()));
+        }
+
+        let auth = resp.json::<AuthResponse>().await?;
+        if !auth.session.valid {
+            return Err(PiHoleError::AuthRejected(auth.session.message.unwrap_or_default()));
+        }
+
+        // Refresh a little early so a request never races the expiry
+        let validity = Duration::from_secs(auth.session.validity.max(0) as u64);
+        Ok(Session {
+            // sid is null when the pihole has no password set
+            sid: auth.session.sid.unwrap_or_default(),
+            validity,
+            expires: Instant::now() + validity.saturating_sub(Duration::from_secs(10)),
+        })
+    }
+
+    // Send a request to the v6 api, logging in (again) when the session is missing or expired
+    async fn v6_request(&self, method: reqwest::Method, path: &str, body: Option<serde_json::Value>) -> Result<reqwest::Response, PiHoleError> {
+        self.v6_request_timeout(method, path, body, REQUEST_TIMEOUT).await
+    }
+
+    // v6_request for calls that take longer than REQUEST_TIMEOUT (the timeout covers reading the body too)
+    async fn v6_request_timeout(&self, method: reqwest::Method, path: &str, body: Option<serde_json::Value>, timeout: Duration) -> Result<reqwest::Response, PiHoleError> {
+        let url = format!("{}/api/{}", self.host, path);
+        let mut session = self.session.lock().await;
+
+        // Only retry once, see v6_reply
+        for attempt in 0..2 {
+            // Get a fresh session if there is none or it timed out
+            let expired = session.as_ref().is_none_or(|s| s.expires <= Instant::now());
+            if expired {
+                *session = Some(self.login().await?);
+            }
+            let current = session.as_mut().unwrap();
+
+            let mut request = self.client.request(method.clone(), &url)
+                .header("X-FTL-SID", &current.sid)
+                .timeout(timeout);
+            if let Some(body) = &body {
+                request = request.json(body);
+            }
+            let resp = request.send().await?;
+
+            let reply = v6_reply(resp.status(), attempt);
+            if reply == V6Reply::LogInAgain {
+                // Session was dropped by the pihole (restart, too many sessions), log in again
+                *session = None;
+                continue;
+            }
+
+            // Every successful call extends the session on the pihole side
+            current.expires = Instant::now() + current.validity.saturating_sub(Duration::from_secs(10));
+
+            if reply == V6Reply::Refused {
+                let status = resp.status();
+                return Err(refused_error(status, &resp.text().await.unwrap_or_default()));
+            }
+            return Ok(resp.error_for_status()?);
+        }
+
+        Err(PiHoleError::AuthRejected("Session rejected by pihole after logging in again".to_string()))
+    }
+
+    // Add or remove (sub) a domain with the legacy api, which answers {"success": bool, "message": "..."}
+    async fn legacy_list_change(&self, change: &str, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        let resp = self.legacy_call(&format!("list={}&{}={}", list.legacy_name(), change, url_encode(domain))).await?;
+        parse_legacy_list_change(&resp)
+    }
+}
+
+// A wrong key gets an empty list back from api.php instead of an error
+fn check_legacy_reply(resp: serde_json::Value) -> Result<serde_json::Value, PiHoleError> {
+    if resp.as_array().is_some_and(|list| list.is_empty()) {
+        return Err(PiHoleError::AuthRejected("Pihole returned no data, check the api key".to_string()));
+    }
+    Ok(resp)
+}
+
+// Read the status out of a legacy enable/disable/status response, {"status": "enabled"}
+fn parse_legacy_status(resp: &serde_json::Value) -> Result<BlockingStatus, PiHoleError> {
+    match resp.get("status").and_then(|status| status.as_str()) {
+        Some("enabled") => Ok(BlockingStatus {enabled: true, timer_remaining: None}),
+        Some("disabled") => Ok(BlockingStatus {enabled: false, timer_remaining: None}),
+        _ => Err(PiHoleError::UnexpectedPayload(format!("Unexpected status response: {}", resp))),
+    }
+}
+
+// Read a legacy list change response, {"success": bool, "message": "..."}
+fn parse_legacy_list_change(resp: &serde_json::Value) -> Result<(), PiHoleError> {
+    match resp.get("success").and_then(|success| success.as_bool()) {
+        Some(true) => Ok(()),
+        Some(false) => Err(PiHoleError::Refused(resp.get("message").and_then(|message| message.as_str()).unwrap_or_default().to_string())),
+        None => Err(PiHoleError::UnexpectedPayload(format!("Unexpected list response: {}", resp))),
+    }
+}
+
+// Read the legacy summaryRaw response
+fn parse_legacy_summary(resp: serde_json::Value) -> Result<Summary, PiHoleError> {
+    let summary = serde_json::from_value::<LegacySummary>(resp)
+        .map_err(|e| PiHoleError::UnexpectedPayload(format!("Unexpected summary response: {}", e)))?;
+    Ok(Summary {
+        queries_today: summary.dns_queries_today,
+        blocked_today: summary.ads_blocked_today,
+        percent_blocked: summary.ads_percentage_today,
+        domains_on_blocklist: summary.domains_being_blocked,
+        clients: summary.unique_clients,
+    })
+}
+
+// Read a legacy list response, {"data": [{"domain": "...", "enabled": 1, "comment": "...", ...}]}
+fn parse_legacy_domains(resp: &serde_json::Value) -> Result<Vec<ListedDomain>, PiHoleError> {
+    let Some(data) = resp.get("data").and_then(|data| data.as_array()) else {
+        return Err(PiHoleError::UnexpectedPayload(format!("Unexpected list response: {}", resp)));
+    };
+
+    let mut domains = Vec::new();
+    for entry in data {
+        let Some(domain) = entry.get("domain").and_then(|domain| domain.as_str()) else {
+            return Err(PiHoleError::UnexpectedPayload(format!("List entry without a domain: {}", entry)));
+        };
+        domains.push(ListedDomain {
+            domain: domain.to_string(),
+            // 1/0 in the legacy api
+            enabled: entry.get("enabled").is_none_or(|enabled| enabled.as_u64() == Some(1) || enabled.as_bool() == Some(true)),
+            comment: entry.get("comment").and_then(|comment| comment.as_str()).filter(|comment| !comment.is_empty()).map(str::to_string),
+        });
+    }
+    Ok(domains)
+}
+
+// What v6_request does with the status of a response
+#[derive(Debug, PartialEq)]
+enum V6Reply {
+    /// 401 on the first try: the session is gone, log in and send it again
+    LogInAgain,
+    /// 400/404: the pihole turned the request down and says why in the body
+    Refused,
+    /// Anything else. Other errors (a second 401, 500, ...) come out of error_for_status
+    Done,
+}
+
+// Only retry once: a second 401 means the key itself is wrong
+fn v6_reply(status: reqwest::StatusCode, attempt: u32) -> V6Reply {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED if attempt == 0 => V6Reply::LogInAgain,
+        reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::NOT_FOUND => V6Reply::Refused,
+        _ => V6Reply::Done,
+    }
+}
+
+// Pass on what the pihole said was wrong with the request (ex. an invalid regex), the status when it said nothing
+fn refused_error(status: reqwest::StatusCode, body: &str) -> PiHoleError {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(body) => match body.error.hint {
+            Some(hint) => PiHoleError::Refused(format!("{} ({})", body.error.message, hint)),
+            None => PiHoleError::Refused(body.error.message),
+        },
+        Err(_) => PiHoleError::Refused(status.to_string()),
+    }
+}
+
+// Read one row of the legacy getAllQueries response:
+// [time, type, domain, client, status, ...], numbers are usually sent as strings
+fn parse_legacy_query(row: &serde_json::Value) -> Option<Query> {
+    let field = |index: usize| row.get(index).map(|value| match value {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    });
+
+    let status = legacy_status(field(4)?.parse().ok()?);
+    Some(Query {
+        time: field(0)?.parse().ok()?,
+        query_type: field(1)?,
+        domain: field(2)?,
+        client: field(3)?,
+        status: status.to_string(),
+        blocked: blocked_status(status),
+    })
+}
+
+// Tidy a line of `pihole -g` output for showing: drop the terminal colour codes
+// and keep what is left after the last \r (the spinners redraw the line in place)
+fn clean_output_line(line: &str) -> String {
+    let mut text = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the sequence up to the letter that ends it, ex. "\x1b[1;32m" or "\x1b[K"
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            text.push(c);
+        }
+    }
+
+    let text = text.rsplit('\r').find(|part| !part.trim().is_empty()).unwrap_or_default();
+    text.trim_end().to_string()
+}
+
+// How a gravity update went by its last line of output. pihole -g marks problems with [✗], ending on one means it gave up
+fn gravity_result(last_line: &str) -> Result<(), PiHoleError> {
+    if last_line.contains("[✗]") {
+        return Err(PiHoleError::Refused(last_line.trim().to_string()));
+    }
+    Ok(())
+}
+
+// Read the output of the legacy queryads.php (the dashboard's "Search Lists" page, same text as `pihole -q`):
+//   data:  Match found in exact blacklist
+//   data:    example.com
+//   data:  Match found in https://example.org/hosts.txt:
+//   data:    example.com
+fn parse_queryads(domain: &str, text: &str) -> Result<SearchResult, PiHoleError> {
+    let mut matches = Vec::new();
+    let mut source = None;
+
+    for line in text.lines() {
+        // Every line is sent as its own event, with an empty line after it
+        let raw = line.strip_prefix("data:").unwrap_or(line);
+        let raw = raw.strip_prefix(' ').unwrap_or(raw);
+        let line = raw.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.contains("Not authorized") {
+            return Err(PiHoleError::AuthRejected("Pihole refused the search, check the api key".to_string()));
+        }
+        if let Some(error) = line.strip_prefix("[ERROR]") {
+            return Err(PiHoleError::Refused(error.trim().trim_start_matches(':').trim().to_string()));
+        }
+
+        if let Some(found) = line.strip_prefix("Match found in ") {
+            let found = found.trim_end_matches(':');
+            source = Some(match found {
+                "exact whitelist" => MatchSource::List(DomainList::Allow),
+                "regex whitelist" => MatchSource::List(DomainList::AllowRegex),
+                "exact blacklist" => MatchSource::List(DomainList::Deny),
+                "regex blacklist" => MatchSource::List(DomainList::DenyRegex),
+                address => MatchSource::Blocklist(address.to_string()),
+            });
+        } else if let Some(found) = source.as_ref().filter(|_| raw.starts_with("  ")) {
+            // The entries are indented further than the "Match found" line above them
+            matches.push(SearchMatch {source: found.clone(), entry: line.to_string(), enabled: true});
+        } else {
+            // Some other message ("No results found ...", "Over 100 results found ...")
+            source = None;
+        }
+    }
+
+    Ok(SearchResult {domain: domain.to_string(), matches})
+}
+
+// Percent-encode a domain or regex for use in a url
+fn url_encode(text: &str) -> String {
+    text.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+#[async_trait]
+impl PiHoleBackend for AuthPiHoleAPI {
+    /// Disable pihole for n seconds
+    async fn disable(&self, seconds: u64) -> Result<BlockingStatus, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            // A timer of null means disable until enabled again
+            let timer = if seconds != 0 {json!(seconds)} else {json!(null)};
+            return self.set_blocking(false, timer).await;
+        }
+
+        self.legacy_request(&format!("disable{}",
+            (if seconds != 0 {format!("={}", seconds)} else {"".to_string()})
+        )).await
     }
 
     /// enable the pihole
-    pub async fn enable(&self) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-        // Format the url
-        let url = format!("{}/admin/api.php?enable&auth={}",
-            self.host,
-            self.key
-        );
+    async fn enable(&self) -> Result<BlockingStatus, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            return self.set_blocking(true, json!(null)).await;
+        }
 
-        // Call the api
-        let resp = self.client.get(url).send()
+        self.legacy_request("enable").await
+    }
+
+    // Retrieve the status of the pihole (enabled or disabled)
+    async fn status(&self) -> Result<BlockingStatus, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            let resp = self.v6_request(reqwest::Method::GET, "dns/blocking", None).await?;
+            return resp.json::<BlockingResponse>().await?.into_status();
+        }
+
+        self.legacy_request("status").await
+    }
+
+    /// Today's numbers from the dashboard summary
+    async fn summary(&self) -> Result<Summary, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            let resp = self.v6_request(reqwest::Method::GET, "stats/summary", None).await?;
+            let summary = resp.json::<SummaryResponse>().await?;
+            return Ok(Summary {
+                queries_today: summary.queries.total,
+                blocked_today: summary.queries.blocked,
+                percent_blocked: summary.queries.percent_blocked,
+                domains_on_blocklist: summary.gravity.domains_being_blocked.max(0) as u64,
+                clients: summary.clients.active,
+            });
+        }
+
+        // summaryRaw has plain numbers, summary formats them for display ("1,234")
+        parse_legacy_summary(self.legacy_call("summaryRaw").await?)
+    }
+
+    /// Entries on one of the domain lists
+    async fn domains(&self, list: DomainList) -> Result<Vec<ListedDomain>, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            let resp = self.v6_request(reqwest::Method::GET, &format!("domains/{}", list.v6_path()), None).await?;
+            let domains = resp.json::<DomainsResponse>().await?.domains.into_iter()
+                .map(|entry| ListedDomain {domain: entry.domain, enabled: entry.enabled, comment: entry.comment})
+                .collect();
+            return Ok(domains);
+        }
+
+        parse_legacy_domains(&self.legacy_call(&format!("list={}", list.legacy_name())).await?)
+    }
+
+    /// Put a domain (or regex) on one of the lists
+    async fn add_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            let body = json!({"domain": domain, "enabled": true});
+            let resp = self.v6_request(reqwest::Method::POST, &format!("domains/{}", list.v6_path()), Some(body)).await?;
+
+            // Duplicates and the like still get a 201, with the reason under processed.errors
+            let resp = resp.json::<serde_json::Value>().await?;
+            if let Some(error) = resp.pointer("/processed/errors/0/error").and_then(|error| error.as_str()) {
+                return Err(PiHoleError::Refused(error.to_string()));
+            }
+            return Ok(());
+        }
+
+        self.legacy_list_change("add", list, domain).await
+    }
+
+    /// Take a domain (or regex) off one of the lists
+    async fn remove_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            self.v6_request(reqwest::Method::DELETE, &format!("domains/{}/{}", list.v6_path(), url_encode(domain)), None).await?;
+            return Ok(());
+        }
+
+        self.legacy_list_change("sub", list, domain).await
+    }
+
+    /// Every list entry matching the domain
+    async fn search(&self, domain: &str) -> Result<SearchResult, PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            let resp = self.v6_request(reqwest::Method::GET, &format!("search/{}?partial=false&N=100", url_encode(domain)), None).await?;
+            let search = resp.json::<SearchResponse>().await?.search;
+
+            let mut matches: Vec<SearchMatch> = search.domains.into_iter()
+                .map(|found| SearchMatch {
+                    source: MatchSource::List(DomainList::new(found.list_type == "allow", found.kind == "regex")),
+                    entry: found.domain,
+                    enabled: found.enabled,
+                })
+                .collect();
+            matches.extend(search.gravity.into_iter().map(|found| SearchMatch {
+                source: if found.list_type == "allow" {MatchSource::AllowSubscription(found.address)} else {MatchSource::Blocklist(found.address)},
+                entry: found.domain,
+                enabled: found.enabled,
+            }));
+            return Ok(SearchResult {domain: domain.to_string(), matches});
+        }
+
+        // api.php can't search, the dashboard's own search page can
+        let url = format!("{}/admin/scripts/pi-hole/php/queryads.php?domain={}&exact&bp&auth={}", self.host, url_encode(domain), self.key);
+        let text = self.client.get(url).send()
             .await?
-            .json::<HashMap<String, String>>()
+            .error_for_status()?
+            .text()
             .await?;
+        parse_queryads(domain, &text)
+    }
+
+    /// Queries after the cursor, oldest first, and the cursor to continue from.
+    /// Without a cursor the last FIRST_QUERIES queries
+    async fn queries(&self, cursor: Option<&QueryCursor>) -> Result<(Vec<Query>, QueryCursor), PiHoleError> {
+        if self.api_version().await? == ApiVersion::V6 {
+            // from= has whole seconds, the ids tell which of that second's queries are new
+            let path = match cursor {
+                Some(cursor) => format!("queries?from={}&length=1000", cursor.time.floor() as u64),
+                None => format!("queries?length={}", FIRST_QUERIES),
+            };
+            let resp = self.v6_request(reqwest::Method::GET, &path, None).await?;
+            let mut entries = resp.json::<QueriesResponse>().await?.queries;
+            entries.reverse();
+
+            let seen_id = cursor.map(|cursor| cursor.last_id);
+            let mut next = cursor.cloned().unwrap_or_default();
+            let mut queries = Vec::new();
+            for entry in entries.into_iter().filter(|entry| seen_id.is_none_or(|id| entry.id > id)) {
+                next.time = next.time.max(entry.time);
+                next.last_id = next.last_id.max(entry.id);
+
+                let status = entry.status.unwrap_or("UNKNOWN".to_string());
+                queries.push(Query {
+                    time: entry.time,
+                    query_type: entry.query_type.unwrap_or_default(),
+                    domain: entry.domain,
+                    client: match entry.client.name.filter(|name| !name.is_empty()) {
+                        Some(name) => format!("{} ({})", entry.client.ip, name),
+                        None => entry.client.ip,
+                    },
+                    blocked: blocked_status(&status),
+                    status,
+                });
+            }
+            return Ok((queries, next));
+        }
+
+        let action = match cursor {
+            Some(cursor) => format!("getAllQueries&from={}&until={}", cursor.time.floor() as u64, chrono::Utc::now().timestamp() + 1),
+            None => format!("getAllQueries={}", FIRST_QUERIES),
+        };
+        let resp = self.legacy_call(&action).await?;
+        let Some(rows) = resp.get("data").and_then(|data| data.as_array()) else {
+            return Err(PiHoleError::UnexpectedPayload(format!("Unexpected query log response: {}", resp)));
+        };
+
+        let mut rows: Vec<Query> = rows.iter().filter_map(parse_legacy_query).collect();
+        rows.sort_by(|a, b| a.time.total_cmp(&b.time));
 
-        // Pass the response back
-        Ok(resp)
+        // Legacy times are whole seconds, so queries in the cursor's second may have been seen already
+        let mut next = cursor.cloned().unwrap_or_default();
+        let mut queries = Vec::new();
+        for query in rows {
+            let key = format!("{} {} {}", query.query_type, query.domain, query.client);
+            if cursor.is_some() && (query.time < next.time || (query.time == next.time && next.seen.contains(&key))) {
+                continue;
+            }
+            if query.time > next.time {
+                next.time = query.time;
+                next.seen.clear();
+            }
+            next.seen.push(key);
+            queries.push(query);
+        }
+        Ok((queries, next))
     }
 
-    // Retrieve the status of the pihole (enabled or disabled)
-    pub async fn status(&self) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    /// Run gravity (pihole -g) on the pihole, handing each line of its output to progress as it comes in
+    async fn update_gravity(&self, progress: &Progress<'_>) -> Result<(), PiHoleError> {
+        // The old api.php has nothing for it, the dashboard's gravity page needs a logged in browser session
+        if self.api_version().await? == ApiVersion::Legacy {
+            return Err(PiHoleError::Refused("Updating gravity needs the Pi-hole v6 api, run pihole -g on older versions".to_string()));
+        }
 
-        // Format the url (Because the api crate doesn't include this call)
-        let url = format!("{}/admin/api.php?status&auth={}", 
-            self.host,
-            self.key
+        let mut resp = self.v6_request_timeout(reqwest::Method::POST, "action/gravity", None, GRAVITY_TIMEOUT).await?;
+
+        // Kept as bytes until a whole line is in, a chunk can end in the middle of a character
+        let mut pending: Vec<u8> = Vec::new();
+        let mut last_line = String::new();
+        let mut show = |bytes: &[u8]| {
+            let line = clean_output_line(&String::from_utf8_lossy(bytes));
+            if !line.trim().is_empty() {
+                progress(&line);
+                last_line = line;
+            }
+        };
+
+        while let Some(chunk) = resp.chunk().await? {
+            pending.extend_from_slice(&chunk);
+            while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                show(&line);
+            }
+        }
+        show(&pending);
+
+        gravity_result(&last_line)
+    }
+
+    /// Open the dashboard in the default browser
+    fn open_dashboard(&self) {
+        // Format address string
+        let addr = format!("{}/admin", self.host);
+
+        // Open the address
+        match open::that(addr) {
+            Ok(_) => {}
+            Err(e) => {log_err!(format!("Error in open: {}", e));}
+        }
+    }
+
+    fn host(&self) -> String {
+        self.host.clone()
+    }
+}
+
+impl BlockingResponse {
+    // Convert to the typed status. "failed" and "unknown" are not usable states
+    fn into_status(self) -> Result<BlockingStatus, PiHoleError> {
+        let enabled = match self.blocking.as_str() {
+            "enabled" => true,
+            "disabled" => false,
+            other => return Err(PiHoleError::UnexpectedPayload(format!("Blocking is {}", other))),
+        };
+
+        Ok(BlockingStatus {
+            enabled,
+            timer_remaining: self.timer.map(|timer| timer.round() as u64),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use reqwest::StatusCode;
+
+    // Answer one http request with the given response. Returns the address to send it to
+    fn serve_once(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(response.as_bytes());
+            // Keep the connection open a while, so an empty response means a timeout
+            std::thread::sleep(Duration::from_secs(2));
+        });
+        address
+    }
+
+    // The error of a GET against the address, the way the api calls turn it into a PiHoleError
+    async fn get_error(address: &str) -> PiHoleError {
+        let client = reqwest::Client::builder().timeout(Duration::from_millis(300)).build().unwrap();
+        let result: Result<serde_json::Value, reqwest::Error> = async {
+            client.get(address).send().await?.error_for_status()?.json().await
+        }.await;
+        result.unwrap_err().into()
+    }
+
+    #[test]
+    fn blocking_response_to_status() {
+        let status = |body: &str| serde_json::from_str::<BlockingResponse>(body).unwrap().into_status();
+
+        assert_eq!(status(r#"{"blocking": "enabled", "timer": null, "took": 0.01}"#), Ok(BlockingStatus {enabled: true, timer_remaining: None}));
+        assert_eq!(status(r#"{"blocking": "disabled", "timer": 29.6}"#), Ok(BlockingStatus {enabled: false, timer_remaining: Some(30)}));
+        assert!(matches!(status(r#"{"blocking": "failed", "timer": null}"#), Err(PiHoleError::UnexpectedPayload(_))));
+        assert!(serde_json::from_str::<BlockingResponse>(r#"{"timer": 5}"#).is_err());
+    }
+
+    #[test]
+    fn v6_status_codes() {
+        assert_eq!(v6_reply(StatusCode::UNAUTHORIZED, 0), V6Reply::LogInAgain);
+        // Still rejected after logging in again, error_for_status makes it AuthRejected
+        assert_eq!(v6_reply(StatusCode::UNAUTHORIZED, 1), V6Reply::Done);
+        assert_eq!(v6_reply(StatusCode::BAD_REQUEST, 0), V6Reply::Refused);
+        assert_eq!(v6_reply(StatusCode::NOT_FOUND, 1), V6Reply::Refused);
+        assert_eq!(v6_reply(StatusCode::OK, 0), V6Reply::Done);
+        assert_eq!(v6_reply(StatusCode::INTERNAL_SERVER_ERROR, 0), V6Reply::Done);
+    }
+
+    #[test]
+    fn v6_refusals() {
+        let body = r#"{"error": {"key": "bad_request", "message": "Invalid regex", "hint": "missing )"}}"#;
+        assert_eq!(refused_error(StatusCode::BAD_REQUEST, body), PiHoleError::Refused("Invalid regex (missing ))".to_string()));
+
+        let body = r#"{"error": {"key": "not_found", "message": "Not found", "hint": null}}"#;
+        assert_eq!(refused_error(StatusCode::NOT_FOUND, body), PiHoleError::Refused("Not found".to_string()));
+
+        assert_eq!(refused_error(StatusCode::NOT_FOUND, "<html>"), PiHoleError::Refused("404 Not Found".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reqwest_errors() {
+        // Nothing listening
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(matches!(get_error(&format!("http://{}", closed)).await, PiHoleError::Unreachable(_)));
+
+        // Connects but never answers
+        assert!(matches!(get_error(&serve_once("")).await, PiHoleError::Timeout(_)));
+
+        let address = serve_once("HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(matches!(get_error(&address).await, PiHoleError::AuthRejected(_)));
+
+        let address = serve_once("HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        assert!(matches!(get_error(&address).await, PiHoleError::UnexpectedPayload(_)));
+
+        let address = serve_once("HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nnot json");
+        assert!(matches!(get_error(&address).await, PiHoleError::UnexpectedPayload(_)));
+    }
+
+    fn found(source: MatchSource, entry: &str, enabled: bool) -> SearchMatch {
+        SearchMatch {source, entry: entry.to_string(), enabled}
+    }
+
+    // An api that already knows it talks to a legacy pihole, so nothing is sent to detect it
+    fn legacy_api() -> AuthPiHoleAPI {
+        let api = AuthPiHoleAPI::new("http://127.0.0.1:9".to_string(), "key".to_string());
+        *api.version.lock().unwrap() = Some(ApiVersion::Legacy);
+        api
+    }
+
+    #[test]
+    fn legacy_status_replies() {
+        assert_eq!(parse_legacy_status(&json!({"status": "enabled"})), Ok(BlockingStatus {enabled: true, timer_remaining: None}));
+        assert_eq!(parse_legacy_status(&json!({"status": "disabled"})), Ok(BlockingStatus {enabled: false, timer_remaining: None}));
+        assert!(matches!(parse_legacy_status(&json!({"status": "paused"})), Err(PiHoleError::UnexpectedPayload(_))));
+        assert!(matches!(parse_legacy_status(&json!({"domains_being_blocked": 5})), Err(PiHoleError::UnexpectedPayload(_))));
+
+        // A wrong key gets []
+        assert!(matches!(check_legacy_reply(json!([])), Err(PiHoleError::AuthRejected(_))));
+        assert_eq!(check_legacy_reply(json!({"status": "enabled"})), Ok(json!({"status": "enabled"})));
+    }
+
+    #[test]
+    fn legacy_summary() {
+        let body = r#"{"domains_being_blocked": 123456, "dns_queries_today": 2000, "ads_blocked_today": 150,
+                       "ads_percentage_today": 7.5, "unique_domains": 300, "unique_clients": 4, "status": "enabled"}"#;
+        assert_eq!(parse_legacy_summary(serde_json::from_str(body).unwrap()), Ok(Summary {
+            queries_today: 2000,
+            blocked_today: 150,
+            percent_blocked: 7.5,
+            domains_on_blocklist: 123456,
+            clients: 4,
+        }));
+
+        // summary instead of summaryRaw has formatted strings
+        let body = r#"{"domains_being_blocked": "123,456", "dns_queries_today": "2,000", "ads_blocked_today": "150",
+                       "ads_percentage_today": "7.5", "unique_clients": "4"}"#;
+        assert!(matches!(parse_legacy_summary(serde_json::from_str(body).unwrap()), Err(PiHoleError::UnexpectedPayload(_))));
+    }
+
+    #[test]
+    fn legacy_lists() {
+        let body = r#"{"data": [
+            {"id": 1, "type": 0, "domain": "example.com", "enabled": 1, "date_added": 1700000000, "comment": "work"},
+            {"id": 2, "type": 0, "domain": "example.org", "enabled": 0, "date_added": 1700000000, "comment": ""}
+        ]}"#;
+        assert_eq!(parse_legacy_domains(&serde_json::from_str(body).unwrap()), Ok(vec![
+            ListedDomain {domain: "example.com".to_string(), enabled: true, comment: Some("work".to_string())},
+            ListedDomain {domain: "example.org".to_string(), enabled: false, comment: None},
+        ]));
+        assert!(matches!(parse_legacy_domains(&json!({"data": [{"id": 1}]})), Err(PiHoleError::UnexpectedPayload(_))));
+        assert!(matches!(parse_legacy_domains(&json!({"status": "enabled"})), Err(PiHoleError::UnexpectedPayload(_))));
+
+        assert_eq!(parse_legacy_list_change(&json!({"success": true, "message": "Added example.com"})), Ok(()));
+        assert_eq!(
+            parse_legacy_list_change(&json!({"success": false, "message": "example.com is already on the list"})),
+            Err(PiHoleError::Refused("example.com is already on the list".to_string())),
         );
+        assert!(matches!(parse_legacy_list_change(&json!({"FTLnotrunning": true})), Err(PiHoleError::UnexpectedPayload(_))));
+    }
 
-        // Call the api
-        let resp = self.client.get(url).send()
-            .await
-            .expect("Http request failed")
-            .json::<HashMap<String, String>>()
-            .await
-            .expect("Json parsing failed");
-        
-        // Pass the response back
-        Ok(resp)
-    }
-}
\ No newline at end of file
+    #[test]
+    fn legacy_queries() {
+        let row = json!(["1700000000", "A", "ads.example.com", "192.168.1.5", "1", "0", "0", "-1"]);
+        assert_eq!(parse_legacy_query(&row), Some(Query {
+            time: 1700000000.0,
+            query_type: "A".to_string(),
+            domain: "ads.example.com".to_string(),
+            client: "192.168.1.5".to_string(),
+            status: "GRAVITY".to_string(),
+            blocked: true,
+        }));
+
+        let row = json!([1700000001, "AAAA", "example.com", "laptop", 2]);
+        let query = parse_legacy_query(&row).unwrap();
+        assert_eq!((query.status.as_str(), query.blocked), ("FORWARDED", false));
+
+        assert_eq!(parse_legacy_query(&json!(["1700000000", "A"])), None);
+        assert_eq!(parse_legacy_query(&json!(["soon", "A", "example.com", "laptop", "2"])), None);
+    }
+
+    #[tokio::test]
+    async fn legacy_refuses_gravity() {
+        let result = legacy_api().update_gravity(&|line: &str| panic!("No output expected, got {}", line)).await;
+        assert!(matches!(result, Err(PiHoleError::Refused(message)) if message.contains("v6")));
+    }
+
+    #[test]
+    fn queryads_matches() {
+        let text = "data:  Match found in exact blacklist\n\n\
+                    data:    ads.example.com\n\n\
+                    data:  Match found in regex whitelist\n\n\
+                    data:    (^|\\.)example\\.com$\n\n\
+                    data:  Match found in https://example.org/hosts.txt:\n\n\
+                    data:    ads.example.com\n\n\
+                    data:    ads.example.com.\n\n";
+        let result = parse_queryads("ads.example.com", text).unwrap();
+
+        assert_eq!(result.domain, "ads.example.com");
+        assert_eq!(result.matches, vec![
+            found(MatchSource::List(DomainList::Deny), "ads.example.com", true),
+            found(MatchSource::List(DomainList::AllowRegex), "(^|\\.)example\\.com$", true),
+            found(MatchSource::Blocklist("https://example.org/hosts.txt".to_string()), "ads.example.com", true),
+            found(MatchSource::Blocklist("https://example.org/hosts.txt".to_string()), "ads.example.com.", true),
+        ]);
+    }
+
+    #[test]
+    fn queryads_nothing_found() {
+        let text = "data:  No results found for example.com within the block lists\n\n";
+        assert_eq!(parse_queryads("example.com", text).unwrap().matches, vec![]);
+
+        // Entries after another message don't belong to the match above it
+        let text = "data:  Match found in exact blacklist\n\
+                    data:    example.com\n\
+                    data:  Over 100 results found, skipping rest of file\n\
+                    data:    example.com\n";
+        assert_eq!(parse_queryads("example.com", text).unwrap().matches.len(), 1);
+    }
+
+    #[test]
+    fn queryads_errors() {
+        assert!(matches!(parse_queryads("example.com", "Not authorized!"), Err(PiHoleError::AuthRejected(_))));
+        assert!(matches!(
+            parse_queryads("example.com", "data:  [ERROR]: Invalid domain\n"),
+            Err(PiHoleError::Refused(message)) if message == "Invalid domain"
+        ));
+    }
+
+    #[test]
+    fn blocked_verdict() {
+        let result = |matches| SearchResult {domain: "example.com".to_string(), matches};
+        let blocklist = MatchSource::Blocklist("https://example.org/hosts.txt".to_string());
+
+        assert!(!result(vec![]).blocked());
+        assert!(result(vec![found(blocklist.clone(), "example.com", true)]).blocked());
+        assert!(result(vec![found(MatchSource::List(DomainList::DenyRegex), "example", true)]).blocked());
+        // The allowlists win
+        assert!(!result(vec![
+            found(blocklist.clone(), "example.com", true),
+            found(MatchSource::List(DomainList::Allow), "example.com", true),
+        ]).blocked());
+        assert!(!result(vec![
+            found(MatchSource::List(DomainList::Deny), "example.com", true),
+            found(MatchSource::AllowSubscription("https://example.org/allow.txt".to_string()), "example.com", true),
+        ]).blocked());
+        // Disabled entries don't count
+        assert!(result(vec![
+            found(blocklist.clone(), "example.com", true),
+            found(MatchSource::List(DomainList::Allow), "example.com", false),
+        ]).blocked());
+        assert!(!result(vec![found(MatchSource::List(DomainList::Deny), "example.com", false)]).blocked());
+    }
+
+    #[test]
+    fn gravity_output_lines() {
+        assert_eq!(clean_output_line("  [i] Neutrino emissions detected...\n"), "  [i] Neutrino emissions detected...");
+        // Colours are dropped
+        assert_eq!(clean_output_line("  \x1b[1;32m[✓]\x1b[0m Done.\x1b[K"), "  [✓] Done.");
+        // A spinner redrawing the line leaves only its last state
+        assert_eq!(clean_output_line("  [i] Pulling blocklist\r  [✓] Pulling blocklist\r   \n"), "  [✓] Pulling blocklist");
+        assert_eq!(clean_output_line("\x1b[K\r\n"), "");
+    }
+
+    #[test]
+    fn gravity_failure_is_the_last_line() {
+        assert_eq!(gravity_result("  [✓] Done."), Ok(()));
+        assert_eq!(gravity_result(""), Ok(()));
+        assert!(matches!(
+            gravity_result("  [✗] Unable to update gravity, no blocklists could be downloaded\n"),
+            Err(PiHoleError::Refused(message)) if message == "[✗] Unable to update gravity, no blocklists could be downloaded"
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+/*
+    Control several piholes (ex. a keepalived primary/secondary pair) as one.
+    Every action is sent to all of them at the same time so blocking is never left half on
+ */
+use std::sync::Arc;
+use async_trait::async_trait;
+use futures::future::join_all;
+use crate::backend::{PiHoleBackend, Progress};
+use crate::config::PiHoleConfig;
+use crate::credentials::{resolve_key, KeyStore};
+use crate::piapi_handler::{AuthPiHoleAPI, BlockingStatus, DomainList, ListedDomain, PiHoleError, Query, QueryCursor, SearchResult, Summary};
+
+/// A single named pihole in the group
+pub struct Instance<B> {
+    pub name: String,
+    pub api: B,
+}
+
+/// Combined blocking state of the group
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupState {
+    /// Every reachable pihole is blocking
+    Enabled,
+    /// Some reachable piholes are blocking and some are not
+    Mixed,
+    /// No reachable pihole is blocking
+    Disabled,
+    /// None of the piholes answered
+    Unreachable,
+    /// Nothing known yet (the tray before its first status check)
+    Unknown,
+}
+
+impl GroupState {
+    /// Name used in menu labels
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupState::Enabled => "enabled",
+            GroupState::Mixed => "mixed",
+            GroupState::Disabled => "disabled",
+            GroupState::Unreachable => "unreachable",
+            GroupState::Unknown => "unknown",
+        }
+    }
+
+    /// Combine the status responses of every pihole into one state.
+    /// Unreachable piholes are left out unless none of them answered
+    pub fn aggregate(statuses: &[Result<BlockingStatus, PiHoleError>]) -> Self {
+        let mut enabled = 0;
+        let mut reachable = 0;
+
+        for status in statuses.iter().flatten() {
+            reachable += 1;
+            if status.enabled {
+                enabled += 1;
+            }
+        }
+
+        if reachable == 0 {
+            GroupState::Unreachable
+        } else if enabled == reachable {
+            GroupState::Enabled
+        } else if enabled == 0 {
+            GroupState::Disabled
+        } else {
+            GroupState::Mixed
+        }
+    }
+}
+
+/// Group of piholes sharing one tray icon. Cheap to clone, the instances are shared
+pub struct PiHoleGroup<B> {
+    instances: Arc<Vec<Instance<B>>>,
+}
+
+// Derive would require B: Clone even though only the Arc is cloned
+impl<B> Clone for PiHoleGroup<B> {
+    fn clone(&self) -> Self {
+        Self {instances: self.instances.clone()}
+    }
+}
+
+impl<B: PiHoleBackend> PiHoleGroup<B> {
+    /// Create a new group. Must contain at least one pihole
+    pub fn new(instances: Vec<Instance<B>>) -> Self {
+        assert!(!instances.is_empty(), "At least one pihole must be configured");
+        Self {instances: Arc::new(instances)}
+    }
+
+    pub fn instances(&self) -> &[Instance<B>] {
+        &self.instances
+    }
+
+    /// Ask every pihole for its status at the same time.
+    /// Results are in the same order as instances()
+    pub async fn statuses(&self) -> Vec<Result<BlockingStatus, PiHoleError>> {
+        join_all(self.instances.iter().map(|instance| instance.api.status())).await
+    }
+
+    // Turn the per-pihole results of an action into a single result.
+    // Any failure fails the whole action, reporting the first pihole that failed
+    fn combine(&self, results: Vec<Result<BlockingStatus, PiHoleError>>) -> Result<BlockingStatus, PiHoleError> {
+        for (instance, result) in self.instances.iter().zip(results.iter()) {
+            if let Err(e) = result {
+                return Err(e.clone().for_host(&instance.name));
+            }
+        }
+
+        Ok(combined_status(&results))
+    }
+
+    // Same for list changes, which have nothing to combine
+    fn all_done(&self, results: Vec<Result<(), PiHoleError>>) -> Result<(), PiHoleError> {
+        for (instance, result) in self.instances.iter().zip(results) {
+            if let Err(e) = result {
+                return Err(e.for_host(&instance.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PiHoleGroup<AuthPiHoleAPI> {
+    /// Connect to the configured piholes, with their keys from the key store
+    pub fn from_config(piholes: Vec<PiHoleConfig>, store: &dyn KeyStore) -> Result<Self, String> {
+        let mut instances = Vec::new();
+
+        for pihole in piholes {
+            let key = resolve_key(store, &pihole)?;
+            instances.push(Instance {
+                name: pihole.name,
+                api: AuthPiHoleAPI::new(pihole.address, key),
+            });
+        }
+
+        Ok(Self::new(instances))
+    }
+}
+
+// Blocking only counts as enabled when every reachable pihole is blocking.
+// The timer is the longest one so it covers the last pihole to resume
+fn combined_status(statuses: &[Result<BlockingStatus, PiHoleError>]) -> BlockingStatus {
+    BlockingStatus {
+        enabled: GroupState::aggregate(statuses) == GroupState::Enabled,
+        timer_remaining: statuses.iter().flatten().filter_map(|status| status.timer_remaining).max(),
+    }
+}
+
+#[async_trait]
+impl<B: PiHoleBackend> PiHoleBackend for PiHoleGroup<B> {
+    /// Aggregated status. Mixed groups are reported as disabled
+    async fn status(&self) -> Result<BlockingStatus, PiHoleError> {
+        let statuses = self.statuses().await;
+
+        if GroupState::aggregate(&statuses) == GroupState::Unreachable {
+            // Pass back the first error, they are all unreachable anyway
+            let (instance, error) = self.instances.iter()
+                .zip(statuses)
+                .find_map(|(instance, status)| status.err().map(|e| (instance, e)))
+                .unwrap();
+            return Err(error.for_host(&instance.name));
+        }
+
+        Ok(combined_status(&statuses))
+    }
+
+    /// Enable every pihole
+    async fn enable(&self) -> Result<BlockingStatus, PiHoleError> {
+        let results = join_all(self.instances.iter().map(|instance| instance.api.enable())).await;
+
+        self.combine(results)
+    }
+
+    /// Disable every pihole for n seconds
+    async fn disable(&self, seconds: u64) -> Result<BlockingStatus, PiHoleError> {
+        let results = join_all(self.instances.iter().map(|instance| instance.api.disable(seconds))).await;
+
+        self.combine(results)
+    }
+
+    /// Numbers of every pihole that answered added up. Blocklists are usually the same
+    /// on each pihole, so that one is the largest instead of the sum
+    async fn summary(&self) -> Result<Summary, PiHoleError> {
+        let results = join_all(self.instances.iter().map(|instance| instance.api.summary())).await;
+
+        let mut total = Summary::default();
+        let mut first_error = None;
+        let mut answered = 0;
+        for (instance, result) in self.instances.iter().zip(results) {
+            match result {
+                Ok(summary) => {
+                    answered += 1;
+                    total.queries_today += summary.queries_today;
+                    total.blocked_today += summary.blocked_today;
+                    total.domains_on_blocklist = total.domains_on_blocklist.max(summary.domains_on_blocklist);
+                    total.clients += summary.clients;
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e.for_host(&instance.name));
+                }
+            }
+        }
+
+        if answered == 0 {
+            return Err(first_error.unwrap());
+        }
+        if total.queries_today > 0 {
+            total.percent_blocked = total.blocked_today as f64 * 100.0 / total.queries_today as f64;
+        }
+        Ok(total)
+    }
+
+    /// The list of the first pihole that answers. The lists are kept the same on every pihole
+    /// (changes go to all of them), so there is no need to ask the others
+    async fn domains(&self, list: DomainList) -> Result<Vec<ListedDomain>, PiHoleError> {
+        let mut first_error = None;
+        for instance in self.instances.iter() {
+            match instance.api.domains(list).await {
+                Ok(domains) => return Ok(domains),
+                Err(e) => {
+                    first_error.get_or_insert(e.for_host(&instance.name));
+                }
+            }
+        }
+        Err(first_error.unwrap())
+    }
+
+    /// Search on the first pihole that answers, they share the same lists
+    async fn search(&self, domain: &str) -> Result<SearchResult, PiHoleError> {
+        let mut first_error = None;
+        for instance in self.instances.iter() {
+            match instance.api.search(domain).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    first_error.get_or_insert(e.for_host(&instance.name));
+                }
+            }
+        }
+        Err(first_error.unwrap())
+    }
+
+    /// The query log of the first (primary) pihole. Following several logs would need a cursor for each,
+    /// and the secondary only sees queries when the primary is down
+    async fn queries(&self, cursor: Option<&QueryCursor>) -> Result<(Vec<Query>, QueryCursor), PiHoleError> {
+        let instance = &self.instances[0];
+        instance.api.queries(cursor).await.map_err(|e| e.for_host(&instance.name))
+    }
+
+    /// Update gravity on every pihole at once. With several, each line of output starts with the pihole's name
+    async fn update_gravity(&self, progress: &Progress<'_>) -> Result<(), PiHoleError> {
+        let several = self.instances.len() > 1;
+        let results = join_all(self.instances.iter().map(|instance| async move {
+            let named = move |line: &str| {
+                if several {
+                    progress(&format!("[{}] {}", instance.name, line));
+                } else {
+                    progress(line);
+                }
+            };
+            instance.api.update_gravity(&named).await
+        })).await;
+
+        self.all_done(results)
+    }
+
+    /// Add to the list on every pihole
+    async fn add_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        let results = join_all(self.instances.iter().map(|instance| instance.api.add_domain(list, domain))).await;
+
+        self.all_done(results)
+    }
+
+    /// Remove from the list on every pihole
+    async fn remove_domain(&self, list: DomainList, domain: &str) -> Result<(), PiHoleError> {
+        let results = join_all(self.instances.iter().map(|instance| instance.api.remove_domain(list, domain))).await;
+
+        self.all_done(results)
+    }
+
+    /// Open the dashboard of the first (primary) pihole
+    fn open_dashboard(&self) {
+        self.instances[0].api.open_dashboard();
+    }
+
+    /// Addresses of every pihole, comma separated
+    fn host(&self) -> String {
+        self.instances.iter().map(|instance| instance.api.host()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(enabled: bool) -> Result<BlockingStatus, PiHoleError> {
+        Ok(BlockingStatus {enabled, timer_remaining: None})
+    }
+
+    fn down() -> Result<BlockingStatus, PiHoleError> {
+        Err(PiHoleError::Unreachable("down".to_string()))
+    }
+
+    #[test]
+    fn aggregate_states() {
+        assert_eq!(GroupState::aggregate(&[status(true), status(true)]), GroupState::Enabled);
+        assert_eq!(GroupState::aggregate(&[status(false), status(false)]), GroupState::Disabled);
+        assert_eq!(GroupState::aggregate(&[status(true), status(false)]), GroupState::Mixed);
+        assert_eq!(GroupState::aggregate(&[down(), down()]), GroupState::Unreachable);
+        assert_eq!(GroupState::aggregate(&[]), GroupState::Unreachable);
+    }
+
+    #[test]
+    fn aggregate_leaves_out_unreachable() {
+        assert_eq!(GroupState::aggregate(&[status(true), down()]), GroupState::Enabled);
+        assert_eq!(GroupState::aggregate(&[down(), status(false)]), GroupState::Disabled);
+        assert_eq!(GroupState::aggregate(&[status(true), down(), status(false)]), GroupState::Mixed);
+    }
+}
@@ -0,0 +1,89 @@
+/*
+    Background status polling.
+    The piholes are asked on the shared runtime and the results are handed to the UI thread over a channel,
+    so a slow or dead pihole never freezes the menu.
+    The UI can stretch the wait between polls (backoff while unreachable) and ask for a poll right away after an action
+ */
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use crate::backend::PiHoleBackend;
+use crate::piapi_handler::{BlockingStatus, PiHoleError, Summary};
+use crate::pihole_group::PiHoleGroup;
+use crate::tray_functions;
+
+/// Result of one poll, one entry per pihole in group order
+pub type Statuses = Vec<Result<BlockingStatus, PiHoleError>>;
+
+/// One round of polling as handed to the UI
+pub struct Poll {
+    pub statuses: Statuses,
+    /// Dashboard numbers of the group, asked alongside the statuses
+    pub summary: Result<Summary, PiHoleError>,
+    /// When the poll was sent
+    pub started: Instant,
+}
+
+/// Handle to the running poller. Clones control the same poller
+#[derive(Clone)]
+pub struct Poller {
+    wake: Arc<Notify>,
+    // Configured interval, used whenever the piholes answer
+    base: Duration,
+    interval: Arc<Mutex<Duration>>,
+    // Last time poll_now was called
+    poked: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Poller {
+    /// Check the status now instead of waiting for the next poll (ex. right after a menu action)
+    pub fn poll_now(&self) {
+        *self.poked.lock().unwrap() = Some(Instant::now());
+        // Stores a wakeup when a poll is in flight, so it polls again straight after
+        self.wake.notify_one();
+    }
+
+    /// Whether poll_now was called after the given time. Every action calls it once done,
+    /// so this tells if the user changed something themselves since then
+    pub fn poked_since(&self, since: Instant) -> bool {
+        self.poked.lock().unwrap().is_some_and(|poked| poked > since)
+    }
+
+    /// The configured wait between polls
+    pub fn base_interval(&self) -> Duration {
+        self.base
+    }
+
+    /// Change the wait between polls, used from the next wait on
+    pub fn set_interval(&self, interval: Duration) {
+        *self.interval.lock().unwrap() = interval;
+    }
+}
+
+/// Start polling every pihole in the background. Each round of results arrives on the returned channel.
+/// Polling stops once the receiver is dropped
+pub fn start<B: PiHoleBackend + 'static>(group: PiHoleGroup<B>, interval: Duration) -> (Poller, mpsc::Receiver<Poll>) {
+    let (tx, rx) = mpsc::channel();
+    let poller = Poller {wake: Arc::new(Notify::new()), base: interval, interval: Arc::new(Mutex::new(interval)), poked: Arc::new(Mutex::new(None))};
+
+    let handle = poller.clone();
+    tray_functions::spawn(async move {
+        loop {
+            // The next poll only starts after this one finished (or timed out), so slow piholes don't pile up requests
+            let started = Instant::now();
+            let (statuses, summary) = futures::join!(group.statuses(), group.summary());
+            if tx.send(Poll {statuses, summary, started}).is_err() {
+                break; // The tray is gone
+            }
+
+            // Wait for the interval, or less when someone asks for a poll
+            let interval = *handle.interval.lock().unwrap();
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = handle.wake.notified() => {}
+            }
+        }
+    });
+
+    (poller, rx)
+}
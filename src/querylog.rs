@@ -0,0 +1,96 @@
+/*
+    Following the pihole's query log, shared by `pihole-switch tail` and the query log window (linux).
+    Only the queries after the last read are fetched each time, see PiHoleBackend::queries
+ */
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use crate::piapi_handler::Query;
+
+/// Time between reads of the query log
+pub const FOLLOW_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Which queries to show. Empty text matches everything
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// Part of the client address or name
+    pub client: String,
+    /// Part of the domain
+    pub domain: String,
+    /// Some(true) for only blocked queries, Some(false) for only allowed ones
+    pub blocked: Option<bool>,
+}
+
+impl Filter {
+    /// True when the query should be shown. Text is matched ignoring case
+    pub fn matches(&self, query: &Query) -> bool {
+        let contains = |text: &str, part: &str| text.to_lowercase().contains(&part.trim().to_lowercase());
+
+        contains(&query.client, &self.client)
+            && contains(&query.domain, &self.domain)
+            && self.blocked.is_none_or(|blocked| blocked == query.blocked)
+    }
+}
+
+/// Local time of a query, ex. "14:03:27"
+pub fn time_of(query: &Query) -> String {
+    match DateTime::from_timestamp(query.time as i64, 0) {
+        Some(time) => time.with_timezone(&Local).format("%H:%M:%S").to_string(),
+        None => "?".to_string(),
+    }
+}
+
+/// "blocked" or "allowed"
+pub fn verdict(query: &Query) -> &'static str {
+    if query.blocked {"blocked"} else {"allowed"}
+}
+
+/// One line for the terminal, ex. "14:03:27  blocked  A     ads.example.com  192.168.1.5 (laptop)  GRAVITY"
+pub fn describe(query: &Query) -> String {
+    format!("{}  {}  {:<5} {}  {}  {}", time_of(query), verdict(query), query.query_type, query.domain, query.client, query.status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(domain: &str, client: &str, blocked: bool) -> Query {
+        Query {
+            time: 0.0,
+            query_type: "A".to_string(),
+            domain: domain.to_string(),
+            client: client.to_string(),
+            status: if blocked {"GRAVITY"} else {"FORWARDED"}.to_string(),
+            blocked,
+        }
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = Filter::default();
+        assert!(filter.matches(&query("ads.example.com", "192.168.1.5 (laptop)", true)));
+        assert!(filter.matches(&query("example.org", "192.168.1.6", false)));
+    }
+
+    #[test]
+    fn filter_by_text() {
+        let ad = query("ads.Example.com", "192.168.1.5 (Laptop)", true);
+        let filter = |client: &str, domain: &str| Filter {client: client.to_string(), domain: domain.to_string(), blocked: None};
+
+        assert!(filter("laptop", "").matches(&ad));
+        assert!(filter(" 192.168.1.5 ", "EXAMPLE").matches(&ad));
+        assert!(!filter("phone", "").matches(&ad));
+        assert!(!filter("laptop", "example.org").matches(&ad));
+    }
+
+    #[test]
+    fn filter_by_verdict() {
+        let ad = query("ads.example.com", "laptop", true);
+        let page = query("example.com", "laptop", false);
+        let blocked = Filter {blocked: Some(true), ..Default::default()};
+        let allowed = Filter {blocked: Some(false), ..Default::default()};
+
+        assert!(blocked.matches(&ad) && !blocked.matches(&page));
+        assert!(allowed.matches(&page) && !allowed.matches(&ad));
+        assert_eq!(verdict(&ad), "blocked");
+    }
+}
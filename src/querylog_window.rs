@@ -0,0 +1,240 @@
+/*
+    The query log window (linux only). Newest query at the top, filters above the list
+    and buttons to allow or block the domain of the selected query.
+    The query log is read in the background (see PiHoleBackend::queries), the window picks up what came in
+ */
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::mpsc;
+use gtk::prelude::*;
+use crate::backend::PiHoleBackend;
+use crate::piapi_handler::{DomainList, PiHoleError, Query};
+use crate::pihole_group::PiHoleGroup;
+use crate::querylog::{self, Filter};
+use crate::tray_functions;
+
+// Most queries kept, older ones are dropped
+const MAX_ROWS: usize = 1000;
+// Where the domain is in the list's columns
+const DOMAIN_COLUMN: i32 = 3;
+
+// What the background tasks send the window
+enum Update {
+    Queries(Vec<Query>),
+    Failed(PiHoleError),
+    // How an allow/block went
+    Message(String),
+}
+
+// The widgets the callbacks need. GTK objects are reference counted, so clones are cheap
+#[derive(Clone)]
+struct Widgets {
+    client: gtk::Entry,
+    domain: gtk::Entry,
+    status: gtk::ComboBoxText,
+    store: gtk::ListStore,
+    view: gtk::TreeView,
+    message: gtk::Label,
+    // Every query received (up to MAX_ROWS), oldest first, so changing a filter can bring back hidden ones
+    queries: Rc<RefCell<Vec<Query>>>,
+}
+
+impl Widgets {
+    fn filter(&self) -> Filter {
+        Filter {
+            client: self.client.text().to_string(),
+            domain: self.domain.text().to_string(),
+            blocked: match self.status.active() {
+                Some(1) => Some(true),
+                Some(2) => Some(false),
+                _ => None,
+            },
+        }
+    }
+
+    // Put a query at the top of the list
+    fn show(&self, query: &Query) {
+        self.store.insert_with_values(Some(0), &[
+            (0, &querylog::time_of(query)),
+            (1, &querylog::verdict(query)),
+            (2, &query.query_type),
+            (3, &query.domain),
+            (4, &query.client),
+            (5, &query.status),
+        ]);
+    }
+
+    // Show the matching queries again after a filter changed
+    fn refill(&self) {
+        let filter = self.filter();
+        self.store.clear();
+        for query in self.queries.borrow().iter().filter(|query| filter.matches(query)) {
+            self.show(query);
+        }
+    }
+
+    // Add newly read queries, dropping the oldest past MAX_ROWS
+    fn add(&self, new: Vec<Query>) {
+        let filter = self.filter();
+        for query in new.iter().filter(|query| filter.matches(query)) {
+            self.show(query);
+        }
+        while let Some(iter) = self.store.iter_nth_child(None, MAX_ROWS as i32) {
+            self.store.remove(&iter);
+        }
+
+        let mut queries = self.queries.borrow_mut();
+        queries.extend(new);
+        let extra = queries.len().saturating_sub(MAX_ROWS);
+        queries.drain(..extra);
+    }
+
+    fn selected_domain(&self) -> Option<String> {
+        let (model, iter) = self.view.selection().selected()?;
+        model.value(&iter, DOMAIN_COLUMN).get::<String>().ok()
+    }
+}
+
+/// Open a query log window. Must be called on the GTK thread
+pub fn open<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>) {
+    let window = gtk::Window::new(gtk::WindowType::Toplevel);
+    window.set_title("Pi-hole query log");
+    window.set_default_size(900, 500);
+    window.set_position(gtk::WindowPosition::Center);
+
+    // Filters, empty means anything
+    let client = gtk::Entry::new();
+    client.set_placeholder_text(Some("Client"));
+    let domain = gtk::Entry::new();
+    domain.set_placeholder_text(Some("Domain contains"));
+    let status = gtk::ComboBoxText::new();
+    for label in ["All queries", "Blocked", "Allowed"] {
+        status.append_text(label);
+    }
+    status.set_active(Some(0));
+
+    let filters = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    filters.pack_start(&client, true, true, 0);
+    filters.pack_start(&domain, true, true, 0);
+    filters.pack_start(&status, false, false, 0);
+
+    // One text column per field, see Widgets::show
+    let store = gtk::ListStore::new(&[gtk::glib::Type::STRING; 6]);
+    let view = gtk::TreeView::with_model(&store);
+    for (index, title) in ["Time", "Result", "Type", "Domain", "Client", "Status"].into_iter().enumerate() {
+        let cell = gtk::CellRendererText::new();
+        let column = gtk::TreeViewColumn::new();
+        column.set_title(title);
+        column.set_resizable(true);
+        TreeViewColumnExt::pack_start(&column, &cell, true);
+        TreeViewColumnExt::add_attribute(&column, &cell, "text", index as i32);
+        view.append_column(&column);
+    }
+    let scrolled = gtk::ScrolledWindow::new(None::<&gtk::Adjustment>, None::<&gtk::Adjustment>);
+    scrolled.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+    scrolled.add(&view);
+
+    // Allow/block the selected query's domain, how it went is shown next to the buttons
+    let message = gtk::Label::new(Some("Reading the query log..."));
+    message.set_xalign(0.0);
+    let allow = gtk::Button::with_label("Allow domain");
+    let block = gtk::Button::with_label("Block domain");
+
+    let actions = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    actions.pack_start(&message, true, true, 0);
+    actions.pack_end(&block, false, false, 0);
+    actions.pack_end(&allow, false, false, 0);
+
+    let content = gtk::Box::new(gtk::Orientation::Vertical, 6);
+    content.set_border_width(8);
+    content.pack_start(&filters, false, false, 0);
+    content.pack_start(&scrolled, true, true, 0);
+    content.pack_start(&actions, false, false, 0);
+    window.add(&content);
+
+    let widgets = Widgets {client, domain, status, store, view, message, queries: Rc::default()};
+    let (sender, receiver) = mpsc::channel();
+
+    // Show the list again whenever a filter changes
+    let widgets_clone = widgets.clone();
+    widgets.client.connect_changed(move |_| widgets_clone.refill());
+    let widgets_clone = widgets.clone();
+    widgets.domain.connect_changed(move |_| widgets_clone.refill());
+    let widgets_clone = widgets.clone();
+    widgets.status.connect_changed(move |_| widgets_clone.refill());
+
+    for (button, list) in [(&allow, DomainList::Allow), (&block, DomainList::Deny)] {
+        let widgets = widgets.clone();
+        let pi_api = pi_api.clone();
+        let sender = sender.clone();
+        button.connect_clicked(move |_| {
+            let Some(domain) = widgets.selected_domain() else {
+                widgets.message.set_text("Select a query first");
+                return;
+            };
+            widgets.message.set_text(&format!("Adding {} to the {}...", domain, list.describe()));
+
+            // Don't hold up the GTK thread while the pihole answers
+            let pi_api = pi_api.clone();
+            let sender = sender.clone();
+            tray_functions::spawn(async move {
+                let text = match tray_functions::add_domain(&pi_api, list, &domain).await {
+                    Ok(()) => format!("Added {} to the {}", domain, list.describe()),
+                    Err(e) => e.to_string(),
+                };
+                let _ = sender.send(Update::Message(text));
+            });
+        });
+    }
+
+    // Read the query log until the window is closed (sending fails once it is gone)
+    tray_functions::spawn(async move {
+        let mut cursor = None;
+        loop {
+            let update = match pi_api.queries(cursor.as_ref()).await {
+                Ok((queries, next)) => {
+                    cursor = Some(next);
+                    Update::Queries(queries)
+                }
+                Err(e) => Update::Failed(e),
+            };
+            if sender.send(update).is_err() {
+                break;
+            }
+            tokio::time::sleep(querylog::FOLLOW_INTERVAL).await;
+        }
+    });
+
+    let closed = Rc::new(Cell::new(false));
+    let closed_clone = closed.clone();
+    window.connect_destroy(move |_| closed_clone.set(true));
+
+    // Pick up the results on the GTK thread
+    // Set while "Reading..." or an error is shown, those go once the pihole answers
+    let mut outdated_message = true;
+    glib::timeout_add_local(std::time::Duration::from_millis(250), move || {
+        if closed.get() {
+            return glib::ControlFlow::Break;
+        }
+
+        while let Ok(update) = receiver.try_recv() {
+            match update {
+                Update::Queries(queries) => {
+                    if outdated_message {
+                        widgets.message.set_text("");
+                        outdated_message = false;
+                    }
+                    widgets.add(queries);
+                }
+                Update::Failed(e) => {
+                    widgets.message.set_text(&e.to_string());
+                    outdated_message = true;
+                }
+                Update::Message(text) => widgets.message.set_text(&text),
+            }
+        }
+        glib::ControlFlow::Continue
+    });
+
+    window.show_all();
+}
@@ -0,0 +1,363 @@
+/*
+    Domains allowed for a while ("Allow domain for...", `pihole-switch allow example.com --for 15m`).
+    The domain goes on the allowlist and when it should come off again is kept in temporary_allows.json next to the log,
+    so it is still taken off after a restart: the tray checks right at startup and then whenever one is due,
+    list commands in the terminal check too
+ */
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, Local, SecondsFormat};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use crate::backend::PiHoleBackend;
+use crate::piapi_handler::{DomainList, PiHoleError};
+use crate::pihole_group::PiHoleGroup;
+use crate::{config, log_info, log_warn, tray_functions};
+
+/// A domain waiting to be taken off the allowlist
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    pub domain: String,
+    /// Addresses of the piholes it still has to come off of, comma separated
+    pub host: String,
+    /// When to take it off again, RFC 3339
+    pub expires: String,
+}
+
+// Longest the tray waits before looking again, in case a command in the terminal added one
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Wait before trying again when a due domain couldn't be removed (pihole down)
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+// Wait between tries while the list is locked by someone else
+const LOCK_RETRY: Duration = Duration::from_millis(50);
+
+// Wakes the tray's expiry loop when a domain was added
+static ADDED: Notify = Notify::const_new();
+
+/// Where the pending expiries are kept
+pub fn path() -> Option<PathBuf> {
+    config::state_dir().map(|dir| dir.join("temporary_allows.json"))
+}
+
+/// Every pending expiry. No file means none
+pub fn load() -> Vec<Pending> {
+    let Some(path) = path() else {
+        return Vec::new();
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+            log_warn!(format!("Ignoring {}: {}", path.display(), e));
+            Vec::new()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            log_warn!(format!("Could not read {}: {}", path.display(), e));
+            Vec::new()
+        }
+    }
+}
+
+// Write the whole list, through a temporary file so a crash can't leave half a file behind
+fn save(pending: &[Pending]) -> Result<(), String> {
+    let path = path().ok_or("No folder for the temporary allows".to_string())?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, serde_json::to_string_pretty(pending).unwrap()).map_err(|e| format!("{}: {}", temp.display(), e))?;
+    std::fs::rename(&temp, &path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// Lock temporary_allows.json.lock, so the tray and commands in the terminal take turns changing the list.
+// The lock goes when the file is dropped (or the process exits). While someone else has it this waits
+// with a sleep between tries, blocking on the lock would hold up the runtime's worker
+async fn lock() -> Result<File, String> {
+    let path = path().ok_or("No folder for the temporary allows".to_string())?.with_extension("json.lock");
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) => tokio::time::sleep(LOCK_RETRY).await,
+            Err(TryLockError::Error(e)) => return Err(format!("Could not lock {}: {}", path.display(), e)),
+        }
+    }
+}
+
+// Load, change and save the list in one go
+async fn update(change: impl FnOnce(&mut Vec<Pending>)) {
+    let _lock = match lock().await {
+        Ok(file) => file,
+        Err(e) => {
+            log_warn!(format!("Could not save the temporary allows: {}", e));
+            return;
+        }
+    };
+    let mut pending = load();
+    change(&mut pending);
+
+    if let Err(e) = save(&pending) {
+        log_warn!(format!("Could not save the temporary allows: {}", e));
+    }
+}
+
+// When an entry is due. One that can't be read is due right away rather than never
+fn expiry(entry: &Pending) -> DateTime<Local> {
+    DateTime::parse_from_rfc3339(&entry.expires)
+        .map(|time| time.with_timezone(&Local))
+        .unwrap_or_default()
+}
+
+// Addresses of the piholes an entry still has to come off of
+fn hosts(entry: &Pending) -> Vec<&str> {
+    entry.host.split(',').map(str::trim).filter(|host| !host.is_empty()).collect()
+}
+
+// Whether the entry was added through this group. Another config's are left for it
+fn belongs_to<B: PiHoleBackend>(entry: &Pending, group: &PiHoleGroup<B>) -> bool {
+    let group_hosts: Vec<String> = group.instances().iter().map(|instance| instance.api.host()).collect();
+    hosts(entry).iter().all(|host| group_hosts.iter().any(|group_host| group_host == host))
+}
+
+/// When the domain comes off the allowlist again, None if it isn't allowed temporarily
+pub fn expires<B: PiHoleBackend>(domain: &str, group: &PiHoleGroup<B>) -> Option<DateTime<Local>> {
+    load().iter()
+        .find(|entry| entry.domain == domain && belongs_to(entry, group))
+        .map(expiry)
+}
+
+/// Allow a domain for the given seconds. Allowing it again while it is still pending moves the end,
+/// a domain that is already on the allowlist for good is left alone
+pub async fn allow_for<B: PiHoleBackend>(group: &PiHoleGroup<B>, domain: &str, seconds: u64) -> Result<(), PiHoleError> {
+    // Checked before anything is added, parse_duration keeps it sane but the time could still be huge
+    let expires = i64::try_from(seconds).ok()
+        .and_then(chrono::TimeDelta::try_seconds)
+        .and_then(|length| Local::now().checked_add_signed(length))
+        .ok_or(PiHoleError::Refused(format!("{} seconds is too long", seconds)))?;
+
+    // Piholes it is still pending on already have it, the end just moves
+    let pending = load().into_iter().find(|entry| entry.domain == domain && belongs_to(entry, group));
+    let mut added: Vec<String> = pending.as_ref().map(|entry| hosts(entry).iter().map(|host| host.to_string()).collect()).unwrap_or_default();
+
+    // Otherwise it would be taken off when the time runs out
+    if pending.is_none() && group.domains(DomainList::Allow).await?.iter().any(|entry| entry.domain == domain) {
+        return Err(PiHoleError::Refused(format!("{} is already on the allowlist", domain)));
+    }
+
+    let missing: Vec<_> = group.instances().iter().filter(|instance| !added.contains(&instance.api.host())).collect();
+    let results = join_all(missing.iter().map(|instance| instance.api.add_domain(DomainList::Allow, domain))).await;
+
+    // Keep the ones that took it even when others failed, so it still comes off those
+    let mut error = None;
+    for (instance, result) in missing.iter().zip(results) {
+        match result {
+            Ok(()) => added.push(instance.api.host()),
+            Err(e) => {
+                error.get_or_insert(e.for_host(&instance.name));
+            }
+        }
+    }
+
+    if !added.is_empty() {
+        update(|pending| {
+            pending.retain(|entry| !(entry.domain == domain && belongs_to(entry, group)));
+            pending.push(Pending {
+                domain: domain.to_string(),
+                host: added.join(", "),
+                expires: expires.to_rfc3339_opts(SecondsFormat::Secs, false),
+            });
+        }).await;
+        ADDED.notify_one();
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Take the domains that are due off the allowlist. The piholes it couldn't be taken off of
+/// stay for the next time, the entry is only dropped once it is off every one of them.
+/// Only touches the ones added through this group, another config's are left for it
+pub async fn expire_due<B: PiHoleBackend>(group: &PiHoleGroup<B>) {
+    let now = Local::now();
+    let due: Vec<Pending> = load().into_iter()
+        .filter(|entry| belongs_to(entry, group) && expiry(entry) <= now)
+        .collect();
+
+    for entry in due {
+        let instances: Vec<_> = group.instances().iter().filter(|instance| hosts(&entry).contains(&instance.api.host().as_str())).collect();
+        let results = join_all(instances.iter().map(|instance| instance.api.remove_domain(DomainList::Allow, &entry.domain))).await;
+
+        let mut failed = Vec::new();
+        for (instance, result) in instances.iter().zip(results) {
+            match result {
+                // Refused when it was already taken off by hand, nothing left to do either way
+                Ok(()) | Err(PiHoleError::Refused(_)) => {}
+                Err(e) => {
+                    log_warn!(format!("Could not end the temporary allow of {} => {}", entry.domain, e.for_host(&instance.name)));
+                    failed.push(instance.api.host());
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            log_info!(format!("Temporary allow of {} ended", entry.domain));
+        }
+
+        // Unless it was allowed again meanwhile (a new end), leave only the piholes that still have it
+        update(|pending| {
+            let Some(index) = pending.iter().position(|other| *other == entry) else {
+                return;
+            };
+            if failed.is_empty() {
+                pending.remove(index);
+            } else {
+                pending[index].host = failed.join(", ");
+            }
+        }).await;
+    }
+}
+
+/// Take temporary allows off again when they are due, starting with any that ran out while the app was closed
+pub fn start<B: PiHoleBackend + 'static>(group: PiHoleGroup<B>) {
+    tray_functions::spawn(async move {
+        loop {
+            expire_due(&group).await;
+
+            // Sleep until the next one is due. One that is still there after expire_due failed, try it again later
+            let next = load().iter().filter(|entry| belongs_to(entry, &group)).map(expiry).min();
+            let wait = match next {
+                Some(next) if next <= Local::now() => RETRY_INTERVAL,
+                Some(next) => (next - Local::now()).to_std().unwrap_or_default().min(CHECK_INTERVAL),
+                None => CHECK_INTERVAL,
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = ADDED.notified() => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakePiHole;
+    use crate::pihole_group::Instance;
+
+    // A group of two fakes with their own addresses. Each test uses its own so they don't share entries
+    fn group(prefix: &str) -> (PiHoleGroup<FakePiHole>, FakePiHole, FakePiHole) {
+        let (first, second) = (FakePiHole::new(true), FakePiHole::new(true));
+        first.set_host(&format!("{}-1", prefix));
+        second.set_host(&format!("{}-2", prefix));
+        let group = PiHoleGroup::new(vec![
+            Instance {name: "first".to_string(), api: first.clone()},
+            Instance {name: "second".to_string(), api: second.clone()},
+        ]);
+        (group, first, second)
+    }
+
+    fn entry(domain: &str, group: &PiHoleGroup<FakePiHole>) -> Option<Pending> {
+        load().into_iter().find(|entry| entry.domain == domain && belongs_to(entry, group))
+    }
+
+    #[tokio::test]
+    async fn expiry_waits_for_every_pihole() {
+        let (group, first, second) = group("expiry");
+
+        allow_for(&group, "example.com", 0).await.unwrap();
+        assert_eq!(entry("example.com", &group).unwrap().host, "expiry-1, expiry-2");
+        assert_eq!(second.listed(DomainList::Allow), ["example.com"]);
+
+        // Off the first one, the second still has it
+        second.set_unreachable(true);
+        expire_due(&group).await;
+        assert!(first.listed(DomainList::Allow).is_empty());
+        assert_eq!(entry("example.com", &group).unwrap().host, "expiry-2");
+
+        second.set_unreachable(false);
+        expire_due(&group).await;
+        assert!(second.listed(DomainList::Allow).is_empty());
+        assert_eq!(entry("example.com", &group), None);
+        // The first one isn't asked again
+        assert_eq!(first.calls().iter().filter(|call| call.starts_with("remove")).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn removed_by_hand_counts_as_done() {
+        let (group, first, _second) = group("by-hand");
+
+        allow_for(&group, "example.org", 0).await.unwrap();
+        first.remove_domain(DomainList::Allow, "example.org").await.unwrap();
+        expire_due(&group).await;
+        assert_eq!(entry("example.org", &group), None);
+    }
+
+    #[tokio::test]
+    async fn partly_added_still_expires() {
+        let (group, first, second) = group("partly");
+
+        second.set_unreachable(true);
+        assert!(matches!(allow_for(&group, "example.net", 3600).await, Err(PiHoleError::Unreachable(_))));
+        assert_eq!(first.listed(DomainList::Allow), ["example.net"]);
+        assert_eq!(entry("example.net", &group).unwrap().host, "partly-1");
+        assert!(expires("example.net", &group).is_some());
+
+        // Allowing again adds it where it is missing and moves the end
+        second.set_unreachable(false);
+        allow_for(&group, "example.net", 0).await.unwrap();
+        assert_eq!(second.listed(DomainList::Allow), ["example.net"]);
+        assert_eq!(entry("example.net", &group).unwrap().host, "partly-1, partly-2");
+
+        expire_due(&group).await;
+        assert!(first.listed(DomainList::Allow).is_empty() && second.listed(DomainList::Allow).is_empty());
+    }
+
+    // The test runtime has a single thread, so blocking on the lock would never let the holder go
+    #[tokio::test]
+    async fn lock_keeps_others_out() {
+        let held = lock().await.unwrap();
+        // Another open of the file (like another process) has to wait
+        let other = File::open(path().unwrap().with_extension("json.lock")).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+
+        let waiting = tokio::spawn(lock());
+        tokio::time::sleep(LOCK_RETRY * 3).await;
+        assert!(!waiting.is_finished());
+
+        drop(held);
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn too_long_is_refused() {
+        let (group, first, _second) = group("too-long");
+
+        assert!(matches!(allow_for(&group, "example.com", u64::MAX).await, Err(PiHoleError::Refused(_))));
+        assert!(first.calls().is_empty());
+        assert_eq!(entry("example.com", &group), None);
+    }
+
+    #[tokio::test]
+    async fn already_allowed_is_left_alone() {
+        let (group, first, _second) = group("already");
+
+        first.add_domain(DomainList::Allow, "example.edu").await.unwrap();
+        assert!(matches!(allow_for(&group, "example.edu", 0).await, Err(PiHoleError::Refused(_))));
+        assert_eq!(entry("example.edu", &group), None);
+    }
+}
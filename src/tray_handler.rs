@@ -1,14 +1,116 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tray_item::{IconSource, TrayItem};
-use crate::piapi_handler::AuthPiHoleAPI;
+use crate::backend::PiHoleBackend;
+use crate::history;
+use crate::pihole_group::{GroupState, PiHoleGroup};
+use crate::piapi_handler::{PiHoleError, Summary};
+use crate::poller::Poll;
 // #[cfg(target_os = "linux")]
 use crate::*;
 
 pub struct TrayIcon {
     pub tray: TrayItem,
-    status: bool,
-    fail_count: u8,
-    fail_limit: u8,
+    state: GroupState,
+    fails: FailCount,
+    // Menu ids of the per-pihole status labels, in group order
+    instance_labels: Vec<u32>,
+    // Menu id of the status line at the top of the menu
+    status_label: Option<u32>,
+    // Text last put on the status line, so the menu is only touched when it changes
+    status_shown: String,
+    // Why the piholes are unreachable, shown in the status line and tooltip
+    last_error: Option<PiHoleError>,
+    timer: DisableTimer,
+    // Menu ids of the "Stats" lines, in the order of stats_lines
+    stats_labels: Vec<u32>,
+    // Menu ids of the "Recent actions" lines, newest first
+    history_labels: Vec<u32>,
+    // history::recorded() when the lines were last filled, None before the first time
+    history_shown: Option<u64>,
+    // Desktop notifications on state changes, None when the session bus isn't there
+    #[cfg(target_os = "linux")]
+    notifier: Option<notifications::Notifier>,
+    // When the poll behind the current state was sent
+    #[cfg(target_os = "linux")]
+    last_poll: Option<Instant>,
+}
+
+/// Longest wait between polls while the piholes are unreachable
+pub const MAX_POLL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Number of actions listed under "Recent actions"
+pub const HISTORY_LINES: usize = 5;
+
+/// Number of lines under "Stats", see stats_lines
+pub const STATS_LINES: usize = 5;
+
+/// When blocking comes back on after a timed disable.
+/// Clones share the same timer so menu callbacks can start it
+#[derive(Clone, Default)]
+pub struct DisableTimer(Arc<Mutex<Option<Instant>>>);
+
+impl DisableTimer {
+    /// Start counting down from now. 0 seconds means disabled until enabled again (no countdown),
+    /// so does a time too far off to count down to (ex. a huge Disable over D-Bus)
+    pub fn start(&self, seconds: u64) {
+        *self.0.lock().unwrap() = if seconds != 0 {Instant::now().checked_add(Duration::from_secs(seconds))} else {None};
+    }
+
+    /// Stop the countdown
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    /// Time left until blocking resumes. None when no countdown is running or it ran out
+    pub fn remaining(&self) -> Option<Duration> {
+        let resume_at = (*self.0.lock().unwrap())?;
+        resume_at.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())
+    }
+}
+
+// Cut text down to max characters, marking the cut with "..."
+fn shorten(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let kept: String = text.chars().take(max.saturating_sub(3)).collect();
+    format!("{}...", kept)
+}
+
+// Put a comma between each group of thousands, 1234567 -> "1,234,567"
+fn group_digits(number: u64) -> String {
+    let digits = number.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
 
+// Text of the "Stats" lines. Values are a dash until the summary comes in (or when it failed)
+fn stats_lines(summary: Option<&Summary>) -> [String; STATS_LINES] {
+    let value = |get: fn(&Summary) -> String| summary.map(get).unwrap_or("-".to_string());
+    [
+        format!("Queries today: {}", value(|s| group_digits(s.queries_today))),
+        format!("Blocked today: {}", value(|s| group_digits(s.blocked_today))),
+        format!("Percent blocked: {}", value(|s| format!("{:.1}%", s.percent_blocked))),
+        format!("Domains on blocklist: {}", value(|s| group_digits(s.domains_on_blocklist))),
+        format!("Clients: {}", value(|s| group_digits(s.clients))),
+    ]
+}
+
+// Format a countdown as m:ss (or h:mm:ss for long ones)
+fn format_countdown(left: Duration) -> String {
+    let secs = left.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
 }
 
 
@@ -58,6 +160,75 @@ fn load_embedded_image(image: &[u8]) -> Data {
     }
 }
 
+/// Counts the polls in a row that got no answer. The tray only shows unreachable
+/// once max_fail is reached, and polls less often past it
+#[derive(Clone, Copy, Debug)]
+pub struct FailCount {
+    count: u8,
+    limit: u8,
+}
+
+impl FailCount {
+    pub fn new(limit: u8) -> Self {
+        Self {count: 0, limit}
+    }
+
+    // handler to reset the count
+    pub fn pass(&mut self) {
+        if self.count != 0 {
+            self.count = 0;
+        }
+    }
+
+    // handler to increment the count
+    pub fn fail(&mut self) -> Result<u8, u8>{
+        // Keep counting past the limit, the poll backoff grows with it
+        self.count = self.count.saturating_add(1);
+
+        if self.count <= self.limit {
+            Ok(self.count)     // Return the count as Ok
+        } else {
+            Err(self.count) // Return the count as Err (limit has been exceeded)
+        }
+    }
+
+    // Handle pass/fail on a given Result<> function
+    pub fn test<F, T, U>(&mut self, func: F) -> Result<T, u8>
+    where
+        F: Fn() -> Result<T, U>,   // The closure should return a value of type T if successful. Not concerned with Err
+    {
+        // call func. If Ok, mark as pass and return the output T
+        if let Ok(value) = func() {
+            self.pass();
+            return Ok(value);
+        }
+
+        // test function output at this point is always Err. map the Ok and Err from fail marker to Err
+        match self.fail() {
+            Ok(count) => Err(count),
+            Err(count) => Err(count)
+        }
+    }
+
+    pub fn max_fail(&self) -> u8 {
+        self.limit
+    }
+
+    // Wait before the next poll. Doubles with every failure after the fail limit
+    // (base, 2x, 4x, ...) up to MAX_POLL_BACKOFF, back to base once the pihole answers
+    pub fn poll_interval(&self, base: Duration) -> Duration {
+        let failures_over = self.count.saturating_sub(self.limit) as u32;
+        if failures_over == 0 {
+            return base;
+        }
+
+        let backoff = 2u32.checked_pow(failures_over)
+            .and_then(|factor| base.checked_mul(factor))
+            .unwrap_or(MAX_POLL_BACKOFF);
+        backoff.min(MAX_POLL_BACKOFF).max(base)
+    }
+}
+
 // add updates for these
 impl TrayIcon {
     // Constructor function to build and setup the trayIcon for linux
@@ -75,13 +246,13 @@ impl TrayIcon {
             })
             .unwrap();
 
-        // Init tray status
-        let status = false;
-        // Init tray fail_count
-        let fail_count = 0;
+        // Init tray state. Nothing is known yet, the blank icon stays until the first status arrives
+        let state = GroupState::Unknown;
+        // Init tray fail count
+        let fails = FailCount::new(fail_limit);
 
 
-        Self {tray, status, fail_count, fail_limit}
+        Self {tray, state, fails, instance_labels: Vec::new(), status_label: None, status_shown: String::new(), last_error: None, timer: DisableTimer::default(), stats_labels: Vec::new(), history_labels: Vec::new(), history_shown: None, notifier: None, last_poll: None}
     }
 
     // Constructor function to build and setup the trayIcon for windows
@@ -90,138 +261,287 @@ impl TrayIcon {
 
         let tray = TrayItem::new(
             title, 
-            IconSource::Resource("APPICON_DISABLED"))
+            IconSource::Resource("APPICON_DEFAULT"))
             .unwrap();
 
-        // Init tray status
-        let status = false;
-        // Init tray fail_count
-        let fail_count = 0;
+        // Init tray state. Nothing is known yet, the blank icon stays until the first status arrives
+        let state = GroupState::Unknown;
+        // Init tray fail count
+        let fails = FailCount::new(fail_limit);
 
 
-        Self {tray, status, fail_count, fail_limit}
+        Self {tray, state, fails, instance_labels: Vec::new(), status_label: None, status_shown: String::new(), last_error: None, timer: DisableTimer::default(), stats_labels: Vec::new(), history_labels: Vec::new(), history_shown: None}
     }
     
-    // handler to reset fail_count
-    pub fn pass(&mut self) {
-        if self.fail_count != 0 {
-            self.fail_count = 0;
+    // Wait before the next poll, see FailCount::poll_interval
+    pub fn poll_interval(&self, base: Duration) -> Duration {
+        self.fails.poll_interval(base)
+    }
+
+    // Display the icon for the given state
+    pub fn show_state(&mut self, state: GroupState) {
+        // Only do this if the state actually changed
+        if self.state != state {
+            self.tray.set_icon(state_icon(state)).unwrap();
+            self.state = state;
         }
     }
 
-    // handler to increment fail_count
-    pub fn fail(&mut self) -> Result<u8, u8>{
+    // State currently shown by the icon
+    pub fn state(&self) -> GroupState {
+        self.state
+    }
 
-        if self.fail_count  < self.fail_limit {
-            self.fail_count += 1; // Do not want to increment past fail_limit
-            Ok(self.fail_count)     // Return the count as Ok
-        } else {
-            Err(self.fail_count) // Return the count as Err (limit has been reached or exceeded)
-        }
+    // Register the menu id of the status line
+    pub fn set_status_label(&mut self, id: u32) {
+        self.status_label = Some(id);
     }
 
+    // Send desktop notifications when the state changes
     #[cfg(target_os = "linux")]
-    pub fn show_enabled(&mut self) {
-        // Only do this if the status is not already enabled
-        if !self.status {
-            // Retrieve image data from embedded image
-            let image_data = load_embedded_image(ENABLED_ICON);
+    pub fn set_notifier(&mut self, notifier: notifications::Notifier) {
+        self.notifier = Some(notifier);
+    }
 
-            self.tray.set_icon(IconSource::Data {
-                data: image_data.data,
-                width: image_data.width,
-                height: image_data.height,
-            }).unwrap(); // set the enabled icon
-            self.status = true;
+    // Handle to the disable countdown, for the menu callbacks
+    pub fn timer(&self) -> DisableTimer {
+        self.timer.clone()
+    }
+
+    // Text for the status line and tooltip
+    pub fn status_text(&self) -> String {
+        match (self.state, self.timer.remaining()) {
+            (GroupState::Enabled, _) => "Blocking enabled".to_string(),
+            (GroupState::Unknown, _) => "Checking status...".to_string(),
+            // The error already reads "Pihole unreachable: ...", "Authentication rejected: ..."
+            (GroupState::Unreachable, _) => match &self.last_error {
+                Some(e) => e.to_string(),
+                None => "Pihole unreachable".to_string(),
+            },
+            (_, Some(left)) => format!("Blocking resumes in {}", format_countdown(left)),
+            (GroupState::Mixed, None) => "Blocking partly disabled".to_string(),
+            (GroupState::Disabled, None) => "Blocking disabled".to_string(),
         }
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn show_disabled(&mut self) {
-        if self.status {
-            // Retrieve image data from embedded image
-            let image_data = load_embedded_image(DISABLED_ICON);
+    // Show the current status text in the menu (and tooltip where supported).
+    // Called every tick so the countdown keeps moving between polls
+    pub fn update_status_text(&mut self) {
+        let text = self.status_text();
+        if text == self.status_shown {
+            return;
+        }
 
-            self.tray.set_icon(IconSource::Data {
-                data: image_data.data,
-                width: image_data.width,
-                height: image_data.height,
-            }).unwrap();
-            self.status = false;
+        // Errors can be long (urls, os messages), keep the menu a sane width
+        if let Some(id) = self.status_label {
+            self.tray.inner_mut().set_menu_item_label(&shorten(&text, 80), id).unwrap();
         }
+
+        // The linux (ksni) tray has no tooltip, the menu line has to do.
+        // Windows cuts tooltips off at 127 characters
+        #[cfg(target_os = "windows")]
+        self.tray.inner_mut().set_tooltip(&shorten(&text, 127)).unwrap();
+
+        self.status_shown = text;
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn show_enabled(&mut self) {
-        // Only do this if the status is not already enabled
-        if !self.status {
-            self.tray.set_icon(IconSource::Resource("APPICON_ENABLED")).unwrap(); // set the enabled icon
-            self.status = true;
-        }
+    // Register the menu id of a pihole's status label (in group order)
+    pub fn add_instance_label(&mut self, id: u32) {
+        self.instance_labels.push(id);
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn show_disabled(&mut self) {
-        if self.status {
-            self.tray.set_icon(IconSource::Resource("APPICON_DISABLED")).unwrap();
-            self.status = false;
+    // Register the menu id of a "Stats" line (in the order of stats_lines)
+    pub fn add_stats_label(&mut self, id: u32) {
+        self.stats_labels.push(id);
+    }
+
+    // Fill the "Stats" lines with the numbers from a poll, dashes when there are none
+    pub fn update_stats(&mut self, summary: Option<&Summary>) {
+        for (label, id) in stats_lines(summary).iter().zip(self.stats_labels.clone()) {
+            self.tray.inner_mut().set_menu_item_label(&format!("    {}", label), id).unwrap();
         }
     }
 
-    // get the status variable value
-    pub fn is_enabled(&mut self) -> bool{
-        return self.status;
+    // Register the menu id of a "Recent actions" line (newest first)
+    pub fn add_history_label(&mut self, id: u32) {
+        self.history_labels.push(id);
     }
 
-    // Handle pass/fail on a given Result<> function
-    pub fn test<F, T, U>(&mut self, func: F) -> Result<T, u8>
-    where
-        F: Fn() -> Result<T, U>,   // The closure should return a value of type T if successful. Not concerned with Err
-    {
-        // call func. If Ok, mark as pass and return the output T
-        if let Ok(value) = func() {
-            self.pass();
-            return Ok(value);
+    // Fill the "Recent actions" lines, again whenever an action was recorded since
+    pub fn update_history(&mut self) {
+        let recorded = history::recorded();
+        if self.history_shown == Some(recorded) {
+            return;
         }
 
-        // test function output at this point is always Err. map the Ok and Err from fail marker to Err
-        match self.fail() {
-            Ok(count) => Err(count),
-            Err(count) => Err(count)
+        let entries = history::recent(self.history_labels.len());
+        for (i, id) in self.history_labels.clone().into_iter().enumerate() {
+            let label = match entries.get(i) {
+                Some(entry) => format!("    {}", shorten(&entry.describe(), 60)),
+                None if i == 0 => "    No actions yet".to_string(),
+                None => String::new(),
+            };
+            self.tray.inner_mut().set_menu_item_label(&label, id).unwrap();
         }
+
+        self.history_shown = Some(recorded);
     }
 
-    pub fn max_fail(&self) -> u8 {
-        return self.fail_limit;
+    // get the status variable value
+    pub fn is_enabled(&mut self) -> bool{
+        self.state == GroupState::Enabled
     }
 
-    pub fn update_status_icon(&mut self, pi_api: &AuthPiHoleAPI) {
-        match self.test(|| {
-            // Use block_on to call the async function in a synchronous context
-            block_on!(async {
-                pi_api.status().await  // Call the async function and await its result
-            })
+    // Show the results of a poll (see poller::start)
+    pub fn update_status_icon<B: PiHoleBackend>(&mut self, group: &PiHoleGroup<B>, poll: &Poll) {
+        let statuses = &poll.statuses[..];
+        #[cfg(target_os = "linux")]
+        let old_state = self.state;
+
+        // Update the per-pihole labels (only present when there is more than one pihole)
+        for ((instance, status), id) in group.instances().iter().zip(statuses.iter()).zip(self.instance_labels.clone()) {
+            let state = GroupState::aggregate(std::slice::from_ref(status));
+            let label = format!("{}: {}", instance.name, state.as_str());
+            self.tray.inner_mut().set_menu_item_label(&label, id).unwrap();
+        }
+
+        // Follow the pihole's own disable timer when it reports one (v6),
+        // otherwise keep the countdown started locally by disable_sec
+        if GroupState::aggregate(statuses) == GroupState::Enabled {
+            self.timer.clear();
+        } else if let Some(left) = statuses.iter().flatten().filter_map(|status| status.timer_remaining).max() {
+            self.timer.start(left);
+        }
+
+        match self.fails.test(|| {
+            // The group only counts as failed when none of the piholes answered
+            match GroupState::aggregate(statuses) {
+                GroupState::Unreachable => Err(()),
+                state => Ok(state),
+            }
         }) {
-            Ok(response) => {
-                // Parse the output of the api call
-                let status = response.get("status").unwrap();
-    
-                // check enabled or disabled
-                if status == "enabled" {
-                    // Display enabled
-                    self.show_enabled();
-                } else {
-                    // Display disabled
-                    self.show_disabled();
-                }
+            Ok(state) => {
+                // Display enabled, mixed or disabled
+                self.last_error = None;
+                self.show_state(state);
             },
             Err(count) => {
-                if count >= self.max_fail() {
-                    // Display disabled
-                    self.show_disabled();
+                // Keep the reason for the status line, naming the pihole when there are several
+                let first_error = group.instances().iter()
+                    .zip(statuses)
+                    .find_map(|(instance, status)| status.as_ref().err().map(|e| (instance, e)));
+                if let Some((instance, e)) = first_error {
+                    self.last_error = Some(if statuses.len() > 1 {e.clone().for_host(&instance.name)} else {e.clone()});
+                }
+
+                // A rejected key won't fix itself by retrying so don't wait for the fail limit
+                let auth_rejected = statuses.iter()
+                    .any(|status| matches!(status, Err(PiHoleError::AuthRejected(_))));
+
+                if count >= self.fails.max_fail() || auth_rejected {
+                    // Log why, only once when the state changes
+                    if self.state != GroupState::Unreachable {
+                        if let Some(e) = &self.last_error {
+                            log_err!(format!("Pihole unreachable => {}", e));
+                        }
+                    }
+
+                    // Display unreachable
+                    self.show_state(GroupState::Unreachable);
+                }
+            }
+        }
+
+        self.update_status_text();
+
+        // Refresh the "Stats" lines
+        match &poll.summary {
+            Ok(summary) => self.update_stats(Some(summary)),
+            Err(e) => {
+                log::debug!("No stats: {}", e);
+                self.update_stats(None);
+            }
+        }
+
+        // Pop up a notification when the state changed
+        #[cfg(target_os = "linux")]
+        {
+            let since = self.last_poll.replace(poll.started);
+            if self.state != old_state {
+                if let Some(notifier) = &self.notifier {
+                    notifier.state_changed(old_state, self.state, since, &self.status_text());
                 }
             }
         }
     }
 
 }
+
+// Icon shown for each state
+#[cfg(target_os = "linux")]
+fn state_icon(state: GroupState) -> IconSource {
+    // Retrieve image data from embedded image
+    let image_data = load_embedded_image(match state {
+        GroupState::Enabled => ENABLED_ICON,
+        GroupState::Mixed => MIXED_ICON,
+        GroupState::Disabled => DISABLED_ICON,
+        GroupState::Unreachable => UNREACHABLE_ICON,
+        GroupState::Unknown => BLANK_ICON,
+    });
+
+    IconSource::Data {
+        data: image_data.data,
+        width: image_data.width,
+        height: image_data.height,
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn state_icon(state: GroupState) -> IconSource {
+    IconSource::Resource(match state {
+        GroupState::Enabled => "APPICON_ENABLED",
+        GroupState::Mixed => "APPICON_MIXED",
+        GroupState::Disabled => "APPICON_DISABLED",
+        GroupState::Unreachable => "APPICON_UNREACHABLE",
+        GroupState::Unknown => "APPICON_DEFAULT",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_start() {
+        let timer = DisableTimer::default();
+        timer.start(30);
+        assert!(timer.remaining().is_some_and(|left| left <= Duration::from_secs(30)));
+        timer.start(0);
+        assert_eq!(timer.remaining(), None);
+
+        // Too far off to count down to, but no panic
+        let too_long = crate::tray_functions::parse_duration("99999999999d").unwrap_or(u64::MAX);
+        timer.start(too_long);
+        assert_eq!(timer.remaining(), None);
+    }
+
+    #[test]
+    fn countdown_format() {
+        assert_eq!(format_countdown(Duration::from_secs(5)), "0:05");
+        assert_eq!(format_countdown(Duration::from_secs(90)), "1:30");
+        assert_eq!(format_countdown(Duration::from_secs(3600 + 61)), "1:01:01");
+    }
+
+    #[test]
+    fn stats_text() {
+        assert_eq!(group_digits(0), "0");
+        assert_eq!(group_digits(999), "999");
+        assert_eq!(group_digits(1234567), "1,234,567");
+
+        assert_eq!(stats_lines(None)[0], "Queries today: -");
+        let summary = Summary {queries_today: 12345, percent_blocked: 12.345, ..Default::default()};
+        let lines = stats_lines(Some(&summary));
+        assert_eq!(lines[0], "Queries today: 12,345");
+        assert_eq!(lines[2], "Percent blocked: 12.3%");
+    }
+}
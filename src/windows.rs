@@ -6,25 +6,37 @@
  */
 use crate::*;
 use std::sync::mpsc;
+use crate::backend::PiHoleBackend;
+use crate::pihole_group::PiHoleGroup;
+use crate::poller::{Poll, Poller};
 
 // Used for rx/tx of the system tray menu
 #[derive(PartialEq)]
 pub enum Message {
     Open,
     Quit,
-    Disable10,
-    Disable30,
-    Disable5min,
+    // Disable for the given seconds (from the presets)
+    Disable(u64),
     Toggle,
+    // Actions for a single pihole, by index in the group
+    ToggleInstance(usize),
+    OpenInstance(usize),
+    UpdateGravity,
 }
 
 /// Mainloop function for windows
 /// pi_api - Pihole API handler
 /// pi_tray - tray handler
-pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::TrayIcon) {
+/// presets - disable buttons to add
+/// poller, status_rx - background status checks and their results
+pub fn main<B: PiHoleBackend + 'static>(pi_api: PiHoleGroup<B>, mut pi_tray:tray_handler::TrayIcon, presets: Vec<config::Preset>, poller: Poller, status_rx: mpsc::Receiver<Poll>) {
     // Setup tx/rx channel
     let (tx, rx) = mpsc::sync_channel(1);
 
+    // Add the status line (shows the disable countdown), updated by update_status_icon
+    let id = pi_tray.tray.inner_mut().add_menu_item_with_id("Checking status...", || {}).unwrap();
+    pi_tray.set_status_label(id);
+
     // Add "Open in Browser" Button
     // Open the pihole dashboard in the default browser
     let open_browser_tx = tx.clone();
@@ -44,33 +56,78 @@ pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::Tray
     })
     .unwrap();
 
-    // Setup disable button
-    // Disable pihole 10 seconds
-    let disable_tx = tx.clone();
-    pi_tray.tray.add_menu_item("Disable 10 Seconds", move || {
-        disable_tx.send(Message::Disable10).unwrap();
-    })
-    .unwrap();
+    // Setup a disable button for each preset
+    for preset in presets {
+        let disable_tx = tx.clone();
+        pi_tray.tray.add_menu_item(&preset.label, move || {
+            disable_tx.send(Message::Disable(preset.seconds)).unwrap();
+        })
+        .unwrap();
+    }
 
-    // Setup disable button
-    // Disable pihole 30 seconds
-    let disable_tx = tx.clone();
-    pi_tray.tray.add_menu_item("Disable 30 Seconds", move || {
-        disable_tx.send(Message::Disable30).unwrap();
-    })
-    .unwrap();
+    // Add a break in the tray
+    pi_tray.tray.inner_mut().add_separator().unwrap();
 
-    // Setup disable button
-    // Disable pihole 5 minutes
-    let disable_tx = tx.clone();
-    pi_tray.tray.add_menu_item("Disable 5 minutes", move || {
-        disable_tx.send(Message::Disable5min).unwrap();
+    // Add the "Update Gravity" Button
+    // Download the blocklists again, the output goes to the log
+    let gravity_tx = tx.clone();
+    pi_tray.tray.add_menu_item("Update Gravity", move || {
+        gravity_tx.send(Message::UpdateGravity).unwrap();
     })
     .unwrap();
 
     // Add a break in the tray
     pi_tray.tray.inner_mut().add_separator().unwrap();
 
+    // Add a section for each pihole when there is more than one
+    if pi_api.instances().len() > 1 {
+        for (index, instance) in pi_api.instances().iter().enumerate() {
+            // Status label, updated by update_status_icon
+            let id = pi_tray.tray.inner_mut().add_menu_item_with_id(&instance.name, || {}).unwrap();
+            pi_tray.add_instance_label(id);
+
+            // Toggle only this pihole
+            let toggle_tx = tx.clone();
+            pi_tray.tray.add_menu_item(&format!("    Toggle {}", instance.name), move || {
+                toggle_tx.send(Message::ToggleInstance(index)).unwrap();
+            })
+            .unwrap();
+
+            // Open only this pihole's dashboard
+            let open_browser_tx = tx.clone();
+            pi_tray.tray.add_menu_item(&format!("    Open {}", instance.name), move || {
+                open_browser_tx.send(Message::OpenInstance(index)).unwrap();
+            })
+            .unwrap();
+        }
+
+        // Add a break in the tray
+        pi_tray.tray.inner_mut().add_separator().unwrap();
+    }
+
+    // Today's numbers from the dashboard, refreshed on every poll.
+    // tray-item can't do submenus, so this and the recent actions get their own sections
+    pi_tray.tray.inner_mut().add_menu_item_with_id("Stats", || {}).unwrap();
+    for _ in 0..tray_handler::STATS_LINES {
+        let id = pi_tray.tray.inner_mut().add_menu_item_with_id("", || {}).unwrap();
+        pi_tray.add_stats_label(id);
+    }
+    pi_tray.update_stats(None);
+
+    // Add a break in the tray
+    pi_tray.tray.inner_mut().add_separator().unwrap();
+
+    // Recent actions, newest first (see history)
+    pi_tray.tray.inner_mut().add_menu_item_with_id("Recent actions", || {}).unwrap();
+    for _ in 0..tray_handler::HISTORY_LINES {
+        let id = pi_tray.tray.inner_mut().add_menu_item_with_id("", || {}).unwrap();
+        pi_tray.add_history_label(id);
+    }
+    pi_tray.update_history();
+
+    // Add a break in the tray
+    pi_tray.tray.inner_mut().add_separator().unwrap();
+
     // Add quit button (exits the app)
     let quit_tx = tx.clone();
     pi_tray.tray.add_menu_item("Quit", move || {
@@ -79,30 +136,73 @@ pub fn main(pi_api: piapi_handler::AuthPiHoleAPI, mut pi_tray:tray_handler::Tray
     .unwrap();
     
     // Enter mainloop to keep app from dying
+    // Status checks run in the background (see poller), the loop only picks up the results
     loop {
-        pi_tray.update_status_icon(&pi_api);
+        // Show whatever came in since the last round
+        while let Ok(poll) = status_rx.try_recv() {
+            // An action finished while this poll was out, so it may show the state from before
+            // (ex. still enabled right after a disable, which would clear the new countdown).
+            // Skip it, poll_now already asked for a fresh one
+            if poller.poked_since(poll.started) {
+                continue;
+            }
+            pi_tray.update_status_icon(&pi_api, &poll);
+            // Back off while the piholes are unreachable
+            poller.set_interval(pi_tray.poll_interval(poller.base_interval()));
+        }
+        // Keep the countdown moving between polls
+        pi_tray.update_status_text();
+        // Show actions from the menu and CLI
+        pi_tray.update_history();
     
         // Handle the button presses from the system tray
-        // Specifically stop here for 50ms because the status above needs to execute
+        // Only wait 100ms so new statuses and the countdown are shown quickly
         if let Ok(message) = rx.recv_timeout(std::time::Duration::from_millis(100)) {
             if message == Message::Open {
                 // Open dashboard in browser
-                block_on!(async {tray_functions::open_browser(&pi_api).await});
+                let pi_api = pi_api.clone();
+                tray_functions::spawn(async move {tray_functions::open_browser(&pi_api).await});
             } else if message == Message::Quit {
                 // Close the application
-                println!("Quit");
                 log_info!("Action Received: Quit");
                 break;
-            } else if message == Message::Disable10 {
-                block_on!(async {tray_functions::disable_sec(&pi_api, 10).await});
-            } else if message == Message::Disable30 {
-                block_on!(async {tray_functions::disable_sec(&pi_api, 30).await});
-            } else if message == Message::Disable5min {
-                block_on!(async {tray_functions::disable_sec(&pi_api, 60*5).await});
+            } else if let Message::Disable(seconds) = message {
+                let pi_api = pi_api.clone();
+                let timer = pi_tray.timer();
+                let poller = poller.clone();
+                tray_functions::spawn(async move {
+                    if tray_functions::disable_sec(&pi_api, seconds).await {
+                        timer.start(seconds);
+                    }
+                    poller.poll_now();
+                });
             } else if message == Message::Toggle {
-                println!("Toggle");
-                log_info!("Action Received: Toggle");
-                block_on!(async {tray_functions::toggle_pihole(&pi_api).await});
+                // Toggling never leaves a countdown running
+                pi_tray.timer().clear();
+                let pi_api = pi_api.clone();
+                let poller = poller.clone();
+                tray_functions::spawn(async move {
+                    tray_functions::toggle_pihole(&pi_api).await;
+                    poller.poll_now();
+                });
+            } else if let Message::ToggleInstance(index) = message {
+                log_info!(format!("Action Received: Toggle {}", pi_api.instances()[index].name));
+                let pi_api = pi_api.clone();
+                let poller = poller.clone();
+                tray_functions::spawn(async move {
+                    tray_functions::toggle_pihole(&pi_api.instances()[index].api).await;
+                    poller.poll_now();
+                });
+            } else if let Message::OpenInstance(index) = message {
+                let pi_api = pi_api.clone();
+                tray_functions::spawn(async move {tray_functions::open_browser(&pi_api.instances()[index].api).await});
+            } else if message == Message::UpdateGravity {
+                // No window for the output here, it goes to the log. update_gravity logs how it ended.
+                // Notifications are linux only (D-Bus), so nothing pops up when it is done
+                let pi_api = pi_api.clone();
+                tray_functions::spawn(async move {
+                    let _ = tray_functions::update_gravity(&pi_api, &|line: &str| log_info!(line)).await;
+                });
             }
         }
     }